@@ -0,0 +1,157 @@
+use std::str;
+use encoding::Encoding;
+
+///
+/// count of bytes inspected to detect the encoding.
+///
+pub const DETECT_SIZE: usize = 64 * 1024;
+
+///
+/// result of encoding detection.
+///
+/// `confidence` is from 0.0 to 1.0. 1.0 means the encoding is certain,
+/// for example it is marked by a byte order mark or the input is ASCII.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub encoding: Encoding,
+    pub confidence: f32,
+}
+
+impl Detection {
+    fn new(encoding: Encoding, confidence: f32) -> Detection {
+        Detection { encoding, confidence }
+    }
+}
+
+///
+/// guess the encoding of the bytes. Only the first `DETECT_SIZE` bytes are inspected.
+///
+pub fn detect(bytes: &[u8]) -> Detection {
+    let bytes = &bytes[..bytes.len().min(DETECT_SIZE)];
+    let truncated = bytes.len() == DETECT_SIZE;
+
+    if let Some((encoding, _)) = Encoding::from_bom(bytes) {
+        return Detection::new(encoding, 1.0);
+    }
+    if let Some(detection) = detect_wide(bytes) {
+        return detection;
+    }
+
+    if bytes.iter().all(|&b| b < 0x80) {
+        if is_iso_2022_jp(bytes) {
+            return Detection::new(Encoding::Iso2022Jp, 0.95);
+        }
+        return Detection::new(Encoding::Utf8, 1.0);
+    }
+
+    if let Some(count) = count_non_ascii_chars(bytes, truncated) {
+        // 長い UTF-8 の並びが偶然レガシーエンコーディングから生まれることはまずない。
+        let confidence = 1.0 - 0.5 / (count as f32 + 1.0);
+        return Detection::new(Encoding::Utf8, confidence.min(0.99));
+    }
+
+    let shift_jis = decode_prefix(bytes, Encoding::ShiftJis, truncated).map(|chars| japanese_ratio(&chars));
+    let euc_jp = decode_prefix(bytes, Encoding::EucJp, truncated).map(|chars| japanese_ratio(&chars));
+    let (encoding, best, other) = match (shift_jis, euc_jp) {
+        (Some(s), Some(e)) if e > s => (Encoding::EucJp, e, Some(s)),
+        (Some(s), e) => (Encoding::ShiftJis, s, e),
+        (None, Some(e)) => (Encoding::EucJp, e, None),
+        (None, None) => (Encoding::ShiftJis, 0.0, None),
+    };
+    if best >= 0.5 {
+        // 両方で読める場合は、もう一方の可能性を割り引く。
+        let confidence = match other {
+            Some(other) => best * 0.9 * (1.0 - other / 2.0),
+            None => best * 0.9,
+        };
+        return Detection::new(encoding, confidence);
+    }
+
+    if decode_prefix(bytes, Encoding::Windows1252, truncated).is_some() {
+        Detection::new(Encoding::Windows1252, 0.3)
+    }else{
+        Detection::new(Encoding::Latin1, 0.2)
+    }
+}
+
+// ASCII が多い UTF-16 / UTF-32 は、0x00 が決まった位置に並ぶ。
+fn detect_wide(bytes: &[u8]) -> Option<Detection> {
+    if bytes.len() < 4 {
+        return None;
+    }
+
+    let mut zeros = [0usize; 4];
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == 0 {
+            zeros[i % 4] += 1;
+        }
+    }
+    let quads = bytes.len() / 4;
+    let ratio = |count: usize| count as f32 / quads as f32;
+
+    if ratio(zeros[2]) > 0.9 && ratio(zeros[3]) > 0.9 && ratio(zeros[0]) < 0.5 {
+        return Some(Detection::new(Encoding::Utf32Le, 0.9));
+    }
+    if ratio(zeros[0]) > 0.9 && ratio(zeros[1]) > 0.9 && ratio(zeros[3]) < 0.5 {
+        return Some(Detection::new(Encoding::Utf32Be, 0.9));
+    }
+
+    let pairs = (bytes.len() / 2) as f32;
+    let even = (zeros[0] + zeros[2]) as f32 / pairs;
+    let odd = (zeros[1] + zeros[3]) as f32 / pairs;
+    if odd > 0.3 && even < 0.05 {
+        return Some(Detection::new(Encoding::Utf16Le, odd.min(0.9)));
+    }
+    if even > 0.3 && odd < 0.05 {
+        return Some(Detection::new(Encoding::Utf16Be, even.min(0.9)));
+    }
+    None
+}
+
+fn is_iso_2022_jp(bytes: &[u8]) -> bool {
+    let escapes: [&[u8]; 4] = [b"\x1b$B", b"\x1b$@", b"\x1b(J", b"\x1b(I"];
+    bytes.windows(3).any(|window| escapes.contains(&window))
+}
+
+// 正しい UTF-8 なら、ASCII 以外の文字の数を返す。
+fn count_non_ascii_chars(bytes: &[u8], truncated: bool) -> Option<usize> {
+    let text = match str::from_utf8(bytes) {
+        Ok(text) => text,
+        // 調べる範囲の末尾で切れた文字は許す。
+        Err(err) if truncated && err.error_len().is_none() => {
+            str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or("")
+        },
+        Err(_) => return None,
+    };
+    Some(text.chars().filter(|c| !c.is_ascii()).count())
+}
+
+fn decode_prefix(bytes: &[u8], encoding: Encoding, truncated: bool) -> Option<Vec<char>> {
+    let mut decoder = encoding.new_decoder();
+    let mut chars = Vec::with_capacity(bytes.len());
+    match decoder.decode(bytes, &mut chars, !truncated) {
+        (_, None) => Some(chars),
+        (_, Some(_)) => None,
+    }
+}
+
+// ASCII 以外の文字のうち、日本語の文字の割合を返す。
+fn japanese_ratio(chars: &[char]) -> f32 {
+    let mut total = 0.0;
+    let mut score = 0.0;
+    for &c in chars.iter().filter(|c| !c.is_ascii()) {
+        total += 1.0;
+        score += match c {
+            '\u{3040}'..='\u{30ff}' | '\u{4e00}'..='\u{9fff}' | '\u{3000}'..='\u{303f}' | '\u{ff01}'..='\u{ff60}' => 1.0,
+            // 半角カナは EUC-JP を Shift_JIS として読んだときにも出やすい。
+            '\u{ff61}'..='\u{ff9f}' => 0.3,
+            _ => 0.0,
+        };
+    }
+    if total == 0.0 {
+        0.0
+    }else{
+        score / total
+    }
+}
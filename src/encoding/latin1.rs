@@ -0,0 +1,14 @@
+use encoding::{Decode, Malformed};
+
+///
+/// decoder of ISO-8859-1, which maps every byte to the code point of the same value.
+///
+#[derive(Debug)]
+pub struct Latin1Decoder;
+
+impl Decode for Latin1Decoder {
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, _last: bool) -> (usize, Option<Malformed>) {
+        chars.extend(bytes.iter().map(|&b| b as char));
+        (bytes.len(), None)
+    }
+}
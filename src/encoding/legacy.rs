@@ -0,0 +1,44 @@
+use encoding_rs::{self, DecoderResult};
+use encoding::{Decode, Malformed};
+
+///
+/// decoder of legacy encodings implemented by encoding_rs, such as Shift_JIS and windows-1252.
+/// The state of ISO-2022-JP escape sequences is kept between calls.
+///
+#[derive(Debug)]
+pub struct LegacyDecoder {
+    decoder: encoding_rs::Decoder,
+    buf: String,
+}
+
+impl LegacyDecoder {
+    pub fn new(encoding: &'static encoding_rs::Encoding) -> LegacyDecoder {
+        LegacyDecoder {
+            decoder: encoding.new_decoder_without_bom_handling(),
+            buf: String::new(),
+        }
+    }
+}
+
+impl Decode for LegacyDecoder {
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> (usize, Option<Malformed>) {
+        let mut total = 0;
+        loop {
+            self.buf.clear();
+            let rest = &bytes[total..];
+            let capacity = self.decoder.max_utf8_buffer_length_without_replacement(rest.len()).unwrap_or(rest.len() * 3 + 16);
+            self.buf.reserve(capacity);
+
+            let (result, size) = self.decoder.decode_to_string_without_replacement(rest, &mut self.buf, last);
+            total += size;
+            chars.extend(self.buf.chars());
+            match result {
+                DecoderResult::InputEmpty => return (total, None),
+                DecoderResult::OutputFull => {},
+                DecoderResult::Malformed(len, extra) => {
+                    return (total, Some(Malformed { len: len as usize, extra: extra as usize }));
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use std::char;
+use std::fmt;
+use std::mem;
+use std::str::{self, Utf8Error};
+use encoding_rs;
+use line_endings::LineBreak;
+use {Error, LineEndings};
+
+mod detect;
+mod latin1;
+mod legacy;
+mod utf8;
+mod utf16;
+mod utf32;
+
+pub use self::detect::{Detection, DETECT_SIZE};
+use self::latin1::Latin1Decoder;
+use self::legacy::LegacyDecoder;
+use self::utf8::Utf8Decoder;
+use self::utf16::Utf16Decoder;
+use self::utf32::Utf32Decoder;
+
+///
+/// Text encodings which CharStream can decode.
+///
+/// Constructors taking an `Encoding` sniff a byte order mark at the start of the input.
+/// If one is found, it is skipped and its encoding is used instead of the given one.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+    ShiftJis,
+    EucJp,
+    Iso2022Jp,
+    ///
+    /// ISO-8859-1. Every byte is the code point of the same value, so 0x80-0x9F are C1 controls.
+    ///
+    Latin1,
+    Iso8859_2,
+    Iso8859_15,
+    Windows1250,
+    Windows1251,
+    ///
+    /// Windows-1252. Unlike ISO-8859-1, 0x80-0x9F are printable characters such as '€' and '“'.
+    /// The bytes undefined in the code page, such as 0x81, are C1 controls as in the WHATWG Encoding Standard.
+    ///
+    Windows1252,
+    Koi8R,
+}
+
+impl Encoding {
+    ///
+    /// name of the encoding.
+    ///
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Utf32Le => "UTF-32LE",
+            Encoding::Utf32Be => "UTF-32BE",
+            Encoding::ShiftJis => "Shift_JIS",
+            Encoding::EucJp => "EUC-JP",
+            Encoding::Iso2022Jp => "ISO-2022-JP",
+            Encoding::Latin1 => "ISO-8859-1",
+            Encoding::Iso8859_2 => "ISO-8859-2",
+            Encoding::Iso8859_15 => "ISO-8859-15",
+            Encoding::Windows1250 => "windows-1250",
+            Encoding::Windows1251 => "windows-1251",
+            Encoding::Windows1252 => "windows-1252",
+            Encoding::Koi8R => "KOI8-R",
+        }
+    }
+
+    ///
+    /// detect the encoding by a byte order mark.
+    /// returns the encoding and the length of the byte order mark.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::Encoding;
+    ///
+    /// assert_eq!(Some((Encoding::Utf16Le, 2)), Encoding::from_bom(&[0xff, 0xfe, 0x41, 0x00]));
+    /// assert_eq!(Some((Encoding::Utf32Le, 4)), Encoding::from_bom(&[0xff, 0xfe, 0x00, 0x00]));
+    /// assert_eq!(None, Encoding::from_bom(b"Hello"));
+    /// ```
+    ///
+    pub fn from_bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+        let encodings = [Encoding::Utf32Le, Encoding::Utf32Be, Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be];
+        for (bom, encoding) in BOMS.iter().zip(encodings.iter()) {
+            if bytes.starts_with(bom) {
+                return Some((*encoding, bom.len()));
+            }
+        }
+        None
+    }
+
+    ///
+    /// guess the encoding of the bytes by a byte order mark, UTF-8 validity and
+    /// frequency of Japanese characters.
+    /// Only the first 64 KiB are inspected.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::Encoding;
+    ///
+    /// let detection = Encoding::detect(&[0x82, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd]);
+    /// assert_eq!(Encoding::ShiftJis, detection.encoding);
+    /// assert_eq!(1.0, Encoding::detect(b"Hello").confidence);
+    /// ```
+    ///
+    pub fn detect(bytes: &[u8]) -> Detection {
+        detect::detect(bytes)
+    }
+
+    fn new_decoder(&self) -> Box<dyn Decode> {
+        match *self {
+            Encoding::Utf8 => Box::new(Utf8Decoder::new()),
+            Encoding::Utf16Le => Box::new(Utf16Decoder::new(false)),
+            Encoding::Utf16Be => Box::new(Utf16Decoder::new(true)),
+            Encoding::Utf32Le => Box::new(Utf32Decoder::new(false)),
+            Encoding::Utf32Be => Box::new(Utf32Decoder::new(true)),
+            Encoding::ShiftJis => Box::new(LegacyDecoder::new(encoding_rs::SHIFT_JIS)),
+            Encoding::EucJp => Box::new(LegacyDecoder::new(encoding_rs::EUC_JP)),
+            Encoding::Iso2022Jp => Box::new(LegacyDecoder::new(encoding_rs::ISO_2022_JP)),
+            // encoding_rs は ISO-8859-1 を windows-1252 として扱うので、自前で復号する。
+            Encoding::Latin1 => Box::new(Latin1Decoder),
+            Encoding::Iso8859_2 => Box::new(LegacyDecoder::new(encoding_rs::ISO_8859_2)),
+            Encoding::Iso8859_15 => Box::new(LegacyDecoder::new(encoding_rs::ISO_8859_15)),
+            Encoding::Windows1250 => Box::new(LegacyDecoder::new(encoding_rs::WINDOWS_1250)),
+            Encoding::Windows1251 => Box::new(LegacyDecoder::new(encoding_rs::WINDOWS_1251)),
+            Encoding::Windows1252 => Box::new(LegacyDecoder::new(encoding_rs::WINDOWS_1252)),
+            Encoding::Koi8R => Box::new(LegacyDecoder::new(encoding_rs::KOI8_R)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+///
+/// how to handle malformed byte sequences.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DecodeMode {
+    ///
+    /// a malformed sequence is an error, and the stream ends before it.
+    ///
+    #[default]
+    Strict,
+
+    ///
+    /// a malformed sequence is replaced by U+FFFD REPLACEMENT CHARACTER.
+    /// For UTF-8, each maximal subpart of a valid sequence is replaced by one U+FFFD.
+    ///
+    Lossy,
+
+    ///
+    /// a malformed sequence is skipped.
+    ///
+    Skip,
+}
+
+///
+/// a malformed byte sequence found by `Decode::decode`.
+///
+/// `len` bytes are malformed, and `extra` bytes after them were consumed too.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Malformed {
+    pub len: usize,
+    pub extra: usize,
+}
+
+///
+/// incremental decoder of an encoding, which is `Send` so CharStream can be sent to another thread.
+///
+pub trait Decode: fmt::Debug + Send {
+    ///
+    /// decode bytes and push chars to `chars`.
+    /// an incomplete sequence at the end of `bytes` is kept for the next call unless `last` is true.
+    /// returns count of consumed bytes, which is less than `bytes.len()` only if a malformed sequence is found.
+    ///
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> (usize, Option<Malformed>);
+
+    ///
+    /// count of bytes which were consumed but are not decoded yet, if the decoder knows it.
+    ///
+    fn pending(&self) -> Option<usize> {
+        None
+    }
+}
+
+///
+/// decoder used by CharStream, which sniffs the byte order mark and tracks byte offsets.
+///
+#[derive(Debug)]
+pub struct Decoder {
+    encoding: Encoding,
+    decoder: Box<dyn Decode>,
+    bom: Option<Vec<u8>>,
+    offset: usize,
+    line: usize,
+    endings: LineEndings,
+    after_cr: bool,
+    history: Vec<u8>,
+    pending: Vec<u8>,
+    mode: DecodeMode,
+    replacements: usize,
+}
+
+// count of consumed bytes remembered to report a malformed sequence which began in the previous input.
+const HISTORY_SIZE: usize = 8;
+
+impl Decoder {
+    ///
+    /// decoder without byte order mark sniffing.
+    ///
+    pub fn new(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding,
+            decoder: encoding.new_decoder(),
+            bom: None,
+            offset: 0,
+            line: 1,
+            endings: LineEndings::default(),
+            after_cr: false,
+            history: Vec::new(),
+            pending: Vec::new(),
+            mode: DecodeMode::Strict,
+            replacements: 0,
+        }
+    }
+
+    ///
+    /// decoder which sniffs the byte order mark at the start of the input.
+    ///
+    pub fn with_bom_sniffing(encoding: Encoding) -> Decoder {
+        let mut decoder = Decoder::new(encoding);
+        decoder.bom = Some(Vec::new());
+        decoder
+    }
+
+    ///
+    /// change how to handle malformed sequences.
+    ///
+    pub fn set_mode(&mut self, mode: DecodeMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> DecodeMode {
+        self.mode
+    }
+
+    ///
+    /// change which chars end a line, for the line of errors.
+    ///
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        self.endings = endings;
+    }
+
+    ///
+    /// count of malformed sequences which were replaced or skipped.
+    ///
+    pub fn replacements(&self) -> usize {
+        self.replacements
+    }
+
+    ///
+    /// start decoding a new input with the same encoding and mode.
+    ///
+    pub fn reset(&mut self) {
+        self.decoder = self.encoding.new_decoder();
+        self.offset = 0;
+        self.line = 1;
+        self.after_cr = false;
+        self.history.clear();
+        self.pending.clear();
+    }
+
+    ///
+    /// count of bytes which were given but are not decoded yet, if it is known.
+    ///
+    pub fn pending(&self) -> Option<usize> {
+        let bom = self.bom.as_ref().map_or(0, |bom| bom.len());
+        self.decoder.pending().map(|len| bom + self.pending.len() + len)
+    }
+
+    ///
+    /// whether the input is decoded as UTF-8 from the current byte.
+    ///
+    pub fn is_utf8(&self) -> bool {
+        self.encoding == Encoding::Utf8 && self.bom.is_none()
+    }
+
+    ///
+    /// decode bytes and push chars to `chars`.
+    /// returns the error of the first malformed sequence.
+    /// The bytes after the malformed sequence are kept and decoded at the next call.
+    ///
+    pub fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> Option<Error> {
+        let mut bytes = bytes;
+        let mut joined = Vec::new();
+        if let Some(mut bom) = self.bom.take() {
+            // BOM を判定できるまで先頭のバイトをためておく。
+            let size = (4 - bom.len()).min(bytes.len());
+            bom.extend_from_slice(&bytes[..size]);
+            bytes = &bytes[size..];
+            if !last && !is_bom_complete(&bom) {
+                self.bom = Some(bom);
+                return None;
+            }
+
+            let mut start = 0;
+            if let Some((encoding, len)) = Encoding::from_bom(&bom) {
+                self.encoding = encoding;
+                self.decoder = encoding.new_decoder();
+                self.offset += len;
+                start = len;
+            }
+            joined = bom.split_off(start);
+        }else if !self.pending.is_empty() {
+            joined = mem::take(&mut self.pending);
+        }
+
+        if joined.is_empty() {
+            self.decode_bytes(bytes, chars, last)
+        }else{
+            joined.extend_from_slice(bytes);
+            self.decode_bytes(&joined, chars, last)
+        }
+    }
+
+    fn decode_bytes(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> Option<Error> {
+        let mut bytes = bytes;
+        loop {
+            let start = chars.len();
+            let (size, malformed) = self.decoder.decode(bytes, chars, last);
+            self.line += count_line_breaks(&chars[start..], self.endings, &mut self.after_cr);
+            let malformed = match malformed {
+                Some(malformed) => malformed,
+                None => {
+                    self.consume(&bytes[..size]);
+                    return None;
+                },
+            };
+
+            match self.mode {
+                DecodeMode::Strict => {
+                    let err = self.error(&bytes[..size], malformed);
+                    self.pending = bytes[size..].to_vec();
+                    return Some(err);
+                },
+                DecodeMode::Lossy => {
+                    // 置き換えた文字を挟んだ "\r" と "\n" は、ひとつの改行にならない。
+                    chars.push(char::REPLACEMENT_CHARACTER);
+                    self.after_cr = false;
+                },
+                DecodeMode::Skip => {},
+            }
+            // 不正な列の後ろから続けて変換する。
+            self.replacements += 1;
+            self.consume(&bytes[..size]);
+            bytes = &bytes[size..];
+        }
+    }
+
+    fn consume(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len();
+        let tail = &bytes[bytes.len().saturating_sub(HISTORY_SIZE)..];
+        self.history.extend_from_slice(tail);
+        let excess = self.history.len().saturating_sub(HISTORY_SIZE);
+        self.history.drain(..excess);
+    }
+
+    fn error(&mut self, consumed: &[u8], malformed: Malformed) -> Error {
+        // 不正な列は、前の入力から始まっていることがある。
+        let mut recent = self.history.clone();
+        recent.extend_from_slice(&consumed[consumed.len().saturating_sub(HISTORY_SIZE)..]);
+        let end = recent.len().saturating_sub(malformed.extra);
+        let bytes = recent[end.saturating_sub(malformed.len)..end].to_vec();
+
+        self.consume(consumed);
+        let offset = self.offset.saturating_sub(malformed.len + malformed.extra);
+        let line = self.line;
+        match self.encoding {
+            Encoding::Utf8 => Error::InvalidUtf8 { offset, line, bytes },
+            encoding => Error::Malformed { encoding, offset, line, bytes },
+        }
+    }
+}
+
+const BOMS: [&[u8]; 5] = [&[0xff, 0xfe, 0x00, 0x00], &[0x00, 0x00, 0xfe, 0xff], &[0xef, 0xbb, 0xbf], &[0xff, 0xfe], &[0xfe, 0xff]];
+
+// UTF-16LE の BOM は UTF-32LE の BOM の先頭と同じなので、4 バイト読むまで決められない。
+fn is_bom_complete(bytes: &[u8]) -> bool {
+    if bytes.len() >= 4 {
+        return true;
+    }
+    match Encoding::from_bom(bytes) {
+        Some((Encoding::Utf16Le, _)) => false,
+        Some(_) => true,
+        None => !BOMS.iter().any(|bom| bom.starts_with(bytes)),
+    }
+}
+
+///
+/// decode all bytes.
+/// returns the chars and count of malformed sequences which were replaced or skipped.
+///
+pub fn decode_all(bytes: &[u8], encoding: Encoding, mode: DecodeMode) -> Result<(Vec<char>, usize), Error> {
+    let mut decoder = Decoder::with_bom_sniffing(encoding);
+    decoder.set_mode(mode);
+    let mut chars = Vec::with_capacity(bytes.len());
+    match decoder.decode(bytes, &mut chars, true) {
+        Some(err) => Err(err),
+        None => Ok((chars, decoder.replacements())),
+    }
+}
+
+///
+/// error of bytes which are not valid UTF-8.
+///  the line is counted by the default `LineEndings`.
+///
+pub fn invalid_utf8(bytes: &[u8], err: &Utf8Error) -> Error {
+    let offset = err.valid_up_to();
+    let len = err.error_len().unwrap_or(bytes.len() - offset);
+    let valid = str::from_utf8(&bytes[..offset]).unwrap_or_default();
+    let chars: Vec<char> = valid.chars().collect();
+    Error::InvalidUtf8 {
+        offset,
+        line: count_line_breaks(&chars, LineEndings::default(), &mut false) + 1,
+        bytes: bytes[offset..offset + len].to_vec(),
+    }
+}
+
+// 改行の数を、CharStream の位置と同じように数える。
+fn count_line_breaks(chars: &[char], endings: LineEndings, after_cr: &mut bool) -> usize {
+    let mut count = 0;
+    for &c in chars {
+        if endings.line_break(c, *after_cr) == LineBreak::Yes {
+            count += 1;
+        }
+        *after_cr = c == '\r';
+    }
+    count
+}
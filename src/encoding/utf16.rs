@@ -0,0 +1,98 @@
+use std::char;
+use encoding::{Decode, Malformed};
+
+///
+/// UTF-16 decoder. An unpaired surrogate is malformed.
+///
+#[derive(Debug)]
+pub struct Utf16Decoder {
+    big_endian: bool,
+    byte: Option<u8>,
+    lead: Option<u16>,
+    unit: Option<u16>,
+}
+
+impl Utf16Decoder {
+    pub fn new(big_endian: bool) -> Utf16Decoder {
+        Utf16Decoder {
+            big_endian,
+            byte: None,
+            lead: None,
+            unit: None,
+        }
+    }
+
+    ///
+    /// decode a code unit. returns malformed if the code unit is an unpaired surrogate
+    /// or the lead surrogate before it is unpaired.
+    ///
+    fn decode_unit(&mut self, unit: u16, chars: &mut Vec<char>) -> Result<(), Malformed> {
+        if let Some(lead) = self.lead.take() {
+            if (0xdc00..0xe000).contains(&unit) {
+                let code_point = 0x10000 + ((u32::from(lead) - 0xd800) << 10) + (u32::from(unit) - 0xdc00);
+                if let Some(c) = char::from_u32(code_point) {
+                    chars.push(c);
+                }
+                return Ok(());
+            }
+
+            // このコードユニットは次の呼び出しで読み直す。
+            self.unit = Some(unit);
+            return Err(Malformed { len: 2, extra: 2 });
+        }
+
+        match unit {
+            0xd800..=0xdbff => self.lead = Some(unit),
+            0xdc00..=0xdfff => return Err(Malformed { len: 2, extra: 0 }),
+            _ => {
+                if let Some(c) = char::from_u32(u32::from(unit)) {
+                    chars.push(c);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Utf16Decoder {
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> (usize, Option<Malformed>) {
+        if let Some(unit) = self.unit.take() {
+            if let Err(malformed) = self.decode_unit(unit, chars) {
+                return (0, Some(Malformed { extra: 0, ..malformed }));
+            }
+        }
+
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            i += 1;
+            let first = match self.byte.take() {
+                Some(first) => first,
+                None => {
+                    self.byte = Some(b);
+                    continue;
+                },
+            };
+
+            let unit = if self.big_endian {
+                (u16::from(first) << 8) | u16::from(b)
+            }else{
+                (u16::from(b) << 8) | u16::from(first)
+            };
+            if let Err(malformed) = self.decode_unit(unit, chars) {
+                return (i, Some(malformed));
+            }
+        }
+
+        if last {
+            if self.lead.take().is_some() {
+                let len = if self.byte.take().is_some() { 3 } else { 2 };
+                return (bytes.len(), Some(Malformed { len, extra: 0 }));
+            }
+            if self.byte.take().is_some() {
+                return (bytes.len(), Some(Malformed { len: 1, extra: 0 }));
+            }
+        }
+        (bytes.len(), None)
+    }
+}
@@ -0,0 +1,52 @@
+use std::char;
+use encoding::{Decode, Malformed};
+
+///
+/// UTF-32 decoder. A surrogate or a value over U+10FFFF is malformed.
+///
+#[derive(Debug)]
+pub struct Utf32Decoder {
+    big_endian: bool,
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl Utf32Decoder {
+    pub fn new(big_endian: bool) -> Utf32Decoder {
+        Utf32Decoder {
+            big_endian,
+            bytes: [0; 4],
+            len: 0,
+        }
+    }
+}
+
+impl Decode for Utf32Decoder {
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> (usize, Option<Malformed>) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.bytes[self.len] = b;
+            self.len += 1;
+            if self.len < 4 {
+                continue;
+            }
+
+            self.len = 0;
+            let code_point = if self.big_endian {
+                u32::from_be_bytes(self.bytes)
+            }else{
+                u32::from_le_bytes(self.bytes)
+            };
+            match char::from_u32(code_point) {
+                Some(c) => chars.push(c),
+                None => return (i + 1, Some(Malformed { len: 4, extra: 0 })),
+            }
+        }
+
+        if last && self.len > 0 {
+            let len = self.len;
+            self.len = 0;
+            return (bytes.len(), Some(Malformed { len, extra: 0 }));
+        }
+        (bytes.len(), None)
+    }
+}
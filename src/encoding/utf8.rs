@@ -0,0 +1,119 @@
+use std::char;
+use std::str;
+use encoding::{Decode, Malformed};
+
+///
+/// UTF-8 decoder.
+/// A malformed sequence is the maximal subpart of a valid sequence, as defined by WHATWG Encoding Standard.
+///
+#[derive(Debug)]
+pub struct Utf8Decoder {
+    code_point: u32,
+    needed: usize,
+    seen: usize,
+    lower: u8,
+    upper: u8,
+}
+
+impl Utf8Decoder {
+    pub fn new() -> Utf8Decoder {
+        Utf8Decoder {
+            code_point: 0,
+            needed: 0,
+            seen: 0,
+            lower: 0x80,
+            upper: 0xbf,
+        }
+    }
+
+    fn reset(&mut self) -> usize {
+        let len = self.seen + 1;
+        *self = Utf8Decoder::new();
+        len
+    }
+}
+
+impl Decode for Utf8Decoder {
+    fn decode(&mut self, bytes: &[u8], chars: &mut Vec<char>, last: bool) -> (usize, Option<Malformed>) {
+        let mut i = 0;
+        loop {
+            if self.needed == 0 {
+                // 正しい部分はまとめて変換する。
+                let valid_up_to = match str::from_utf8(&bytes[i..]) {
+                    Ok(_) => bytes.len() - i,
+                    Err(err) => err.valid_up_to(),
+                };
+                if let Ok(s) = str::from_utf8(&bytes[i..i + valid_up_to]) {
+                    chars.extend(s.chars());
+                }
+                i += valid_up_to;
+            }
+            if i >= bytes.len() {
+                break;
+            }
+
+            let b = bytes[i];
+            if self.needed == 0 {
+                i += 1;
+                match b {
+                    0xc2..=0xdf => {
+                        self.needed = 1;
+                        self.code_point = u32::from(b & 0x1f);
+                    },
+                    0xe0..=0xef => {
+                        if b == 0xe0 {
+                            self.lower = 0xa0;
+                        }else if b == 0xed {
+                            self.upper = 0x9f;
+                        }
+                        self.needed = 2;
+                        self.code_point = u32::from(b & 0x0f);
+                    },
+                    0xf0..=0xf4 => {
+                        if b == 0xf0 {
+                            self.lower = 0x90;
+                        }else if b == 0xf4 {
+                            self.upper = 0x8f;
+                        }
+                        self.needed = 3;
+                        self.code_point = u32::from(b & 0x07);
+                    },
+                    _ => return (i, Some(Malformed { len: 1, extra: 0 })),
+                }
+                continue;
+            }
+
+            if b < self.lower || b > self.upper {
+                // このバイトは次の文字の先頭として読み直す。
+                let len = self.reset();
+                return (i, Some(Malformed { len, extra: 0 }));
+            }
+
+            i += 1;
+            self.lower = 0x80;
+            self.upper = 0xbf;
+            self.code_point = (self.code_point << 6) | u32::from(b & 0x3f);
+            self.seen += 1;
+            if self.seen == self.needed {
+                if let Some(c) = char::from_u32(self.code_point) {
+                    chars.push(c);
+                }
+                self.reset();
+            }
+        }
+
+        if last && self.needed != 0 {
+            let len = self.reset();
+            return (bytes.len(), Some(Malformed { len, extra: 0 }));
+        }
+        (bytes.len(), None)
+    }
+
+    fn pending(&self) -> Option<usize> {
+        if self.needed == 0 {
+            Some(0)
+        }else{
+            Some(self.seen + 1)
+        }
+    }
+}
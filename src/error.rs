@@ -0,0 +1,94 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use Encoding;
+
+///
+/// Errors which can occur while reading chars from CharStream.
+///
+#[derive(Debug)]
+pub enum Error {
+    ///
+    /// an I/O error from the underlying reader.
+    ///
+    Io(io::Error),
+
+    ///
+    /// bytes which are not valid UTF-8.
+    /// `offset` is the byte offset of the first invalid byte from the start of the input,
+    /// `line` is the line number where the bytes are, counted by `LineEndings` of the stream, and `bytes` are the invalid bytes.
+    ///
+    InvalidUtf8 { offset: usize, line: usize, bytes: Vec<u8> },
+
+    ///
+    /// bytes which are malformed in the encoding other than UTF-8.
+    /// `offset` is the byte offset of the first malformed byte from the start of the input,
+    /// `line` is the line number where the bytes are, counted by `LineEndings` of the stream, and `bytes` are the malformed bytes.
+    ///
+    Malformed { encoding: Encoding, offset: usize, line: usize, bytes: Vec<u8> },
+
+    ///
+    /// the operation is not supported by this kind of CharStream.
+    ///
+    UnsupportedOperation(&'static str),
+
+    ///
+    /// an error while opening or reading the file opened by `CharStream::open`.
+    /// `path` is the path given to `open`, and `error` is the error which occurred.
+    ///
+    InFile { path: PathBuf, error: Box<Error> },
+
+    ///
+    /// the file is already being read by an outer `CharStream::include`.
+    /// `path` is the file to include, and `chain` are the paths of the files being read, from the outermost one.
+    ///
+    IncludeCycle { path: PathBuf, chain: Vec<PathBuf> },
+
+    ///
+    /// `CharStream::splice` is nested deeper than `limit`.
+    ///
+    SpliceLimit { limit: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::InvalidUtf8 { offset, line, ref bytes } => {
+                write!(f, "invalid UTF-8 sequence [{}] at line {}, byte offset {}", hex(bytes), line, offset)
+            },
+            Error::Malformed { encoding, offset, line, ref bytes } => {
+                write!(f, "malformed {} sequence [{}] at line {}, byte offset {}", encoding, hex(bytes), line, offset)
+            },
+            Error::UnsupportedOperation(message) => write!(f, "unsupported operation: {}", message),
+            Error::InFile { ref path, ref error } => write!(f, "{}: {}", path.display(), error),
+            Error::IncludeCycle { ref path, ref chain } => {
+                let chain: Vec<String> = chain.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "{} is included recursively from {}", path.display(), chain.join(" -> "))
+            },
+            Error::SpliceLimit { limit } => write!(f, "splice is nested deeper than {}", limit),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::InFile { ref error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///
+/// Handle to control CharStream made by `CharStream::follow`.
+///
+/// The handle can be cloned and sent to other threads.
+///
+/// Example:
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use char_stream::CharStream;
+///
+/// let mut stream = CharStream::follow("/var/log/syslog").unwrap();
+/// let handle = stream.follow_handle().unwrap();
+/// handle.set_poll_interval(Duration::from_millis(500));
+///
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_secs(60));
+///     handle.stop();
+/// });
+///
+/// while let Some(line) = stream.read_line() {
+///     println!("{}", line);
+/// }
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct FollowHandle {
+    state: Arc<FollowState>,
+}
+
+#[derive(Debug)]
+struct FollowState {
+    stopped: AtomicBool,
+    poll_interval: Mutex<Duration>,
+}
+
+impl FollowHandle {
+    pub(crate) fn new() -> FollowHandle {
+        FollowHandle {
+            state: Arc::new(FollowState {
+                stopped: AtomicBool::new(false),
+                poll_interval: Mutex::new(Duration::from_millis(100)),
+            }),
+        }
+    }
+
+    ///
+    /// stop following. the stream ends after the chars already read.
+    ///
+    pub fn stop(&self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+    }
+
+    ///
+    /// whether `stop` was called.
+    ///
+    pub fn is_stopped(&self) -> bool {
+        self.state.stopped.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// interval to check whether the file grows. default is 100 milliseconds.
+    ///
+    pub fn poll_interval(&self) -> Duration {
+        match self.state.poll_interval.lock() {
+            Ok(interval) => *interval,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    ///
+    /// change interval to check whether the file grows.
+    ///
+    pub fn set_poll_interval(&self, interval: Duration) {
+        match self.state.poll_interval.lock() {
+            Ok(mut guard) => *guard = interval,
+            Err(poisoned) => *poisoned.into_inner() = interval,
+        }
+    }
+}
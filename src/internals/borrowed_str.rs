@@ -0,0 +1,58 @@
+///
+/// reads chars directly from a borrowed str without copying it.
+///
+#[derive(Debug)]
+pub struct InternalStr<'a> {
+    s: &'a str,
+    index: usize,
+}
+
+impl<'a> InternalStr<'a> {
+    pub fn new(s: &'a str) -> InternalStr<'a> {
+        InternalStr {
+            s,
+            index: 0,
+        }
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        let result = self.peek();
+        if let Some(c) = result {
+            self.index += c.len_utf8();
+        }
+
+        result
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        self.s[self.index..].chars().next()
+    }
+
+    ///
+    /// byte offset of the next char.
+    ///
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    ///
+    /// move to the byte offset which is on a char boundary.
+    ///
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    ///
+    /// the whole str which this reads.
+    ///
+    pub fn source(&self) -> &'a str {
+        self.s
+    }
+
+    ///
+    /// the str which is not read yet.
+    ///
+    pub fn rest(&self) -> &'a str {
+        &self.s[self.index..]
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use encoding::DecodeMode;
+use {CharStream, Error, LineEndings, Position};
+
+///
+/// reads chars from CharStreams in sequence.
+///
+#[derive(Debug)]
+pub struct InternalChain<'a> {
+    sources: VecDeque<CharStream<'a>>,
+    index: usize,
+    insert_newline: bool,
+    last: Option<char>,
+    newline: bool,
+    error: Option<Error>,
+    replacements: usize,
+}
+
+impl<'a> InternalChain<'a> {
+    pub fn new(sources: Vec<CharStream<'a>>) -> InternalChain<'a> {
+        InternalChain {
+            sources: sources.into_iter().collect(),
+            index: 0,
+            insert_newline: false,
+            last: None,
+            newline: false,
+            error: None,
+            replacements: 0,
+        }
+    }
+
+    pub fn set_insert_newline(&mut self, insert: bool) {
+        self.insert_newline = insert;
+    }
+
+    pub fn set_buffer_size(&mut self, size: usize) {
+        for source in self.sources.iter_mut() {
+            source.set_buffer_size(size);
+        }
+    }
+
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        for source in self.sources.iter_mut() {
+            source.set_line_endings(endings);
+        }
+    }
+
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        for source in self.sources.iter_mut() {
+            source.set_decode_mode(mode);
+        }
+    }
+
+    pub fn replacements(&self) -> usize {
+        self.replacements + self.sources.iter().map(|source| source.replacements()).sum::<usize>()
+    }
+
+    pub fn skip_malformed(&mut self) -> bool {
+        let skipped = match self.sources.front_mut() {
+            Some(source) => source.skip_malformed(),
+            None => false,
+        };
+        if skipped {
+            self.error = None;
+        }
+        skipped
+    }
+
+    ///
+    /// index of the source which is read now.
+    ///
+    pub fn source_index(&self) -> Option<usize> {
+        if self.sources.is_empty() {
+            None
+        }else{
+            Some(self.index)
+        }
+    }
+
+    pub fn source_path(&self) -> Option<&Path> {
+        self.sources.front().and_then(|source| source.path())
+    }
+
+    pub fn source_position(&self) -> Option<Position> {
+        self.sources.front().map(|source| source.position())
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_next() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_peek() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    ///
+    /// after an error is returned, the rest of the source is skipped.
+    ///
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        let result = self.try_peek()?;
+        if result.is_some() {
+            if self.newline {
+                self.newline = false;
+                self.next_source();
+            }else if let Some(source) = self.sources.front_mut() {
+                source.next();
+            }
+            self.last = result;
+        }
+
+        Ok(result)
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        loop {
+            if self.newline {
+                return Ok(Some('\n'));
+            }
+
+            let source = match self.sources.front_mut() {
+                Some(source) => source,
+                None => return Ok(None),
+            };
+            if let Some(c) = source.try_peek()? {
+                return Ok(Some(c));
+            }
+            if self.sources.len() == 1 {
+                return Ok(None);
+            }
+
+            // 改行で終わらないソースの後には、改行を挟む。
+            if self.insert_newline && self.last.is_some() && self.last != Some('\n') {
+                self.newline = true;
+            }else{
+                self.next_source();
+            }
+        }
+    }
+
+    // 最後のソースは、位置を返せるように残しておく。
+    fn next_source(&mut self) {
+        if self.sources.len() > 1 {
+            if let Some(source) = self.sources.pop_front() {
+                self.replacements += source.replacements();
+            }
+            self.index += 1;
+            self.last = None;
+        }
+    }
+}
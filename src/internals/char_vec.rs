@@ -1,26 +1,24 @@
+use CharSource;
+
 #[derive(Debug)]
 pub struct InternalCharVec {
     chars: Vec<char>,
     index: usize,
-    back_index: Option<usize>,
+    back_index: usize,
 }
 
 impl InternalCharVec {
     pub fn new(chars: Vec<char>) -> InternalCharVec {
-        let len = chars.len();
-        let mut back_index = None;
-        if len > 0 {
-            back_index = Some(len - 1);
-        }
+        let back_index = chars.len();
         InternalCharVec {
-            chars: chars,
+            chars,
             index: 0,
-            back_index: back_index,
+            back_index,
         }
     }
 
     pub fn next(&mut self) -> Option<char> {
-        if self.index >= self.chars.len() {
+        if self.index >= self.back_index {
             return None;
         }
 
@@ -30,7 +28,7 @@ impl InternalCharVec {
     }
 
     pub fn peek(&mut self) -> Option<char> {
-        if self.index >= self.chars.len() {
+        if self.index >= self.back_index {
             return None;
         }
 
@@ -38,26 +36,56 @@ impl InternalCharVec {
     }
 
     pub fn next_back(&mut self) -> Option<char> {
-        if let None = self.back_index {
+        if self.back_index <= self.index {
             return None;
         }
 
-        let index = self.back_index.unwrap();
-        let result = Some(self.chars[index]);
-        if index == 0 {
-            self.back_index = None;
-        }else{
-            self.back_index = Some(index - 1);
-        }
-        result
+        // back_index は次に後ろから読む文字の一つ後を指す。
+        self.back_index -= 1;
+        Some(self.chars[self.back_index])
     }
 
     pub fn peek_back(&mut self) -> Option<char> {
-        if let None = self.back_index {
+        if self.back_index <= self.index {
             return None;
         }
 
-        let index = self.back_index.unwrap();
-        Some(self.chars[index])
+        Some(self.chars[self.back_index - 1])
+    }
+
+    pub fn nth(&mut self, n: usize) -> Option<char> {
+        self.index = self.index.saturating_add(n).min(self.back_index);
+        self.next()
+    }
+
+    pub fn nth_back(&mut self, n: usize) -> Option<char> {
+        self.back_index = self.back_index.saturating_sub(n).max(self.index);
+        self.next_back()
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn set_index(&mut self, index: usize) {
+        self.index = index.min(self.back_index);
+    }
+
+    pub fn len(&self) -> usize {
+        self.back_index - self.index
+    }
+}
+
+impl CharSource for InternalCharVec {
+    fn next(&mut self) -> Option<char> {
+        InternalCharVec::next(self)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        InternalCharVec::peek(self)
+    }
+
+    fn position(&self) -> usize {
+        self.index
     }
 }
@@ -1,109 +1,40 @@
-use std::io::Read;
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, Seek};
 use std::fs::File;
-use internals::InternalCharVec;
+use std::path::Path;
+use encoding::Decoder;
+use internals::{InternalReader, InternalReverseFile};
+use Error;
 
-#[derive(Debug)]
-pub struct InternalFile {
-    reader: BufReader<File>,
-    buf: Option<InternalCharVec>,
-    is_eof: bool,
-}
-
-impl InternalFile {
-    pub fn open(path: &str) -> Result<InternalFile, &'static str> {
-        if let Ok(file) = File::open(path) {
-            let reader = BufReader::new(file);
-            let mut f = InternalFile {
-                reader: reader,
-                buf: None,
-                is_eof: false,
-            };
-            f.read_next_line();
-
-            Ok(f)
-        }else{
-            Err("can't open file.")
-        }
-    }
-
-    pub fn new(file: File) -> InternalFile {
-        let reader = BufReader::new(file);
-        let mut f = InternalFile {
-            reader: reader,
-            buf: None,
-            is_eof: false,
-        };
-        f.read_next_line();
-
-        f
-    }
-
-    pub fn next(&mut self) -> Option<char> {
-        if self.is_eof {
-            return None;
-        }
-
-        let mut result = None;
-        if let Some(ref mut char_vec) = self.buf {
-            result = char_vec.next();
-        }
-        self.check_next_char_and_reset_buf_if_need();
-
-        result
-    }
-
-    pub fn peek(&mut self) -> Option<char> {
-        if self.is_eof {
-            return None;
-        }
-
-        let mut result = None;
-        if let Some(ref mut char_vec) = self.buf {
-            result = char_vec.peek();
-        }
+pub type InternalFile = InternalReader<BufReader<File>>;
 
-        result
+impl InternalReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<InternalFile, Error> {
+        let file = File::open(path)?;
+        Ok(InternalFile::from_file(file))
     }
 
-    fn check_next_char_and_reset_buf_if_need(&mut self){
-        // 次に読める文字がない場合、次の行を先読みする。
-        let mut need_read = false;
-        if let Some(ref mut char_vec) = self.buf {
-            if let None = char_vec.peek() {
-                need_read = true;
-            }
-        }else{
-            need_read = true;
-        }
-        if need_read {
-            self.read_next_line();
-        }
+    pub fn from_file(file: File) -> InternalFile {
+        InternalReader::new(BufReader::new(file))
     }
 
-    fn read_next_line(&mut self){
-        let mut buffer = String::new();
-        if let Ok(_) = self.reader.read_line(&mut buffer) {
-            let char_vec = InternalCharVec::new(buffer.chars().collect());
-            self.buf = Some(char_vec);
-        }else{
-            self.is_eof = true;
-        }
+    pub fn from_file_with_decoder(file: File, decoder: Decoder) -> InternalFile {
+        InternalReader::with_decoder(BufReader::new(file), decoder)
     }
 
-    pub fn read_and_get_all_chars(mut self) -> Vec<char> {
-        self.is_eof = true;
-        let mut buffer = String::new();
-        if let Some(mut char_vec) = self.buf {
-            while let Some(c) = char_vec.next() {
-                buffer.push(c);
-            }
-        }
-        while let Ok(size) = self.reader.read_to_string(&mut buffer) {
-            if size == 0 {
-                break;
-            }
-        }
-        buffer.chars().collect()
+    ///
+    /// convert to the reader from both ends of the file.
+    ///  `chars` are read before the rest of the file.
+    ///
+    pub fn into_reverse_file(self, mut chars: Vec<char>) -> Result<InternalReverseFile, Error> {
+        // 読み込んだがまだ文字になっていないバイトは、ファイルから読み直す。
+        let pending = self.decoder().pending().unwrap_or(0) as u64;
+        let mode = self.decoder().mode();
+        let (mut reader, rest) = self.into_inner()?;
+        chars.extend(rest);
+
+        let front = reader.get_mut().stream_position()? - reader.buffer().len() as u64 - pending;
+        let mut file = InternalReverseFile::new(reader.into_inner(), front, chars)?;
+        file.set_mode(mode);
+        Ok(file)
     }
 }
@@ -0,0 +1,237 @@
+use std::fs::{self, File, Metadata};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use encoding::{Decoder, Encoding};
+use internals::{InternalCharVec, DEFAULT_BUFFER_SIZE};
+use {CharSource, Error, FollowHandle};
+
+///
+/// reads a file like `tail -f`.
+/// At the end of the file, waits for appended data instead of ending the stream.
+///
+#[derive(Debug)]
+pub struct InternalFollow {
+    path: PathBuf,
+    file: File,
+    id: Option<(u64, u64)>,
+    bytes: Vec<u8>,
+    buf: InternalCharVec,
+    decoder: Decoder,
+    handle: FollowHandle,
+    is_eof: bool,
+    error: Option<Error>,
+    malformed: bool,
+    count: usize,
+    offset: u64,
+}
+
+impl InternalFollow {
+    pub fn open<P: AsRef<Path>>(path: P, handle: FollowHandle) -> Result<InternalFollow, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let id = file_id(&file.metadata()?);
+
+        Ok(InternalFollow {
+            path,
+            file,
+            id,
+            bytes: vec![0; DEFAULT_BUFFER_SIZE],
+            buf: InternalCharVec::new(Vec::new()),
+            decoder: Decoder::new(Encoding::Utf8),
+            handle,
+            is_eof: false,
+            error: None,
+            malformed: false,
+            count: 0,
+            offset: 0,
+        })
+    }
+
+    pub fn handle(&self) -> &FollowHandle {
+        &self.handle
+    }
+
+    ///
+    /// change count of bytes which are read and decoded at once.
+    ///
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.bytes = vec![0; size.max(1)];
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut Decoder {
+        &mut self.decoder
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        let result = self.peek();
+        if result.is_some() {
+            self.buf.next();
+            self.count += 1;
+        }
+
+        result
+    }
+
+    ///
+    /// caution: at the end of the file, this blocks the thread until data is appended or the handle is stopped.
+    ///  see `wait`.
+    ///
+    pub fn peek(&mut self) -> Option<char> {
+        loop {
+            if let Some(c) = self.buf.peek() {
+                return Some(c);
+            }
+            if self.is_eof {
+                return None;
+            }
+            if self.handle.is_stopped() {
+                self.is_eof = true;
+                return None;
+            }
+            if self.read_next_chunk() {
+                self.wait();
+            }
+        }
+    }
+
+    ///
+    /// sleep for the poll interval of the handle before checking the file again.
+    ///  so `stop` ends the stream within the poll interval.
+    ///
+    fn wait(&self) {
+        thread::sleep(self.handle.poll_interval());
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        match self.next() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        match self.peek() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    fn take_error(&mut self) -> Result<Option<char>, Error> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    pub fn skip_malformed(&mut self) -> bool {
+        if !self.malformed {
+            return false;
+        }
+        self.malformed = false;
+        self.error = None;
+        self.is_eof = false;
+        true
+    }
+
+    ///
+    /// read and decode the next chunk of the file.
+    ///  returns true if nothing was read at the end of the file.
+    ///
+    fn read_next_chunk(&mut self) -> bool {
+        match self.file.read(&mut self.bytes) {
+            Ok(0) => {
+                if let Err(err) = self.check_rotation() {
+                    self.error = Some(err);
+                    self.is_eof = true;
+                    return false;
+                }
+                return true;
+            },
+            Ok(size) => {
+                self.offset += size as u64;
+
+                // 書き込み途中の文字は、続きが追記されるまで Decoder に残る。
+                let mut chars = Vec::with_capacity(size);
+                if let Some(err) = self.decoder.decode(&self.bytes[..size], &mut chars, false) {
+                    self.error = Some(err);
+                    self.malformed = true;
+                    self.is_eof = true;
+                }
+                self.buf = InternalCharVec::new(chars);
+            },
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => {
+                self.error = Some(Error::Io(err));
+                self.is_eof = true;
+            },
+        }
+        false
+    }
+
+    ///
+    /// reopen the file if it was rotated, or read from the start if it was truncated.
+    ///
+    fn check_rotation(&mut self) -> Result<(), Error> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // ローテーション中でファイルがない場合は、次の確認まで待つ。
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Error::Io(err)),
+        };
+
+        let id = file_id(&metadata);
+        if id != self.id {
+            let file = File::open(&self.path)?;
+            self.id = file_id(&file.metadata()?);
+            self.file = file;
+            self.restart();
+        }else if metadata.len() < self.offset {
+            self.file = File::open(&self.path)?;
+            self.restart();
+        }
+        Ok(())
+    }
+
+    fn restart(&mut self){
+        self.offset = 0;
+        self.decoder.reset();
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl CharSource for InternalFollow {
+    fn next(&mut self) -> Option<char> {
+        InternalFollow::next(self)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        InternalFollow::peek(self)
+    }
+
+    fn position(&self) -> usize {
+        self.count
+    }
+
+    fn try_next(&mut self) -> Result<Option<char>, Error> {
+        InternalFollow::try_next(self)
+    }
+
+    fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        InternalFollow::try_peek(self)
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use encoding::DecodeMode;
+use {CharStream, Error, LineEndings, Position};
+
+///
+/// reads chars from the innermost of nested CharStreams,
+/// and resumes the outer stream at the end of the inner one.
+///
+#[derive(Debug)]
+pub struct InternalInclude<'a> {
+    stack: Vec<CharStream<'a>>,
+    // 循環を見つけるための正規化したパス。
+    canonical: Vec<Option<PathBuf>>,
+    buffer_size: Option<usize>,
+    line_endings: Option<LineEndings>,
+    decode_mode: Option<DecodeMode>,
+    error: Option<Error>,
+    replacements: usize,
+}
+
+impl<'a> InternalInclude<'a> {
+    pub fn new(stream: CharStream<'a>) -> InternalInclude<'a> {
+        let canonical = stream.path().and_then(|path| fs::canonicalize(path).ok());
+        InternalInclude {
+            stack: vec![stream],
+            canonical: vec![canonical],
+            buffer_size: None,
+            line_endings: None,
+            decode_mode: None,
+            error: None,
+            replacements: 0,
+        }
+    }
+
+    ///
+    /// open the file and read it before the rest of the current stream.
+    ///  a relative path is resolved from the directory of the current stream.
+    ///  `pending` are chars pushed back to the current stream, which are read after the file.
+    ///
+    pub fn include(&mut self, path: &Path, pending: &mut VecDeque<char>) -> Result<(), Error> {
+        let path = self.resolve(path);
+        let canonical = match fs::canonicalize(&path) {
+            Ok(canonical) => canonical,
+            Err(err) => return Err(Error::InFile { path, error: Box::new(Error::Io(err)) }),
+        };
+        if self.canonical.iter().any(|included| included.as_ref() == Some(&canonical)) {
+            let chain = self.chain().into_iter().filter_map(|(path, _)| path.map(|path| path.to_path_buf())).collect();
+            return Err(Error::IncludeCycle { path, chain });
+        }
+
+        let mut stream = CharStream::open(&path)?;
+        if let Some(size) = self.buffer_size {
+            stream.set_buffer_size(size);
+        }
+        if let Some(endings) = self.line_endings {
+            stream.set_line_endings(endings);
+        }
+        if let Some(mode) = self.decode_mode {
+            stream.set_decode_mode(mode);
+        }
+        if let Some(current) = self.stack.last_mut() {
+            while let Some(c) = pending.pop_back() {
+                current.unread(c);
+            }
+        }
+        self.stack.push(stream);
+        self.canonical.push(Some(canonical));
+        Ok(())
+    }
+
+    ///
+    /// paths and positions of the nested streams, from the outermost one.
+    ///
+    pub fn chain(&self) -> Vec<(Option<&Path>, Position)> {
+        self.stack.iter().map(|stream| (stream.path(), stream.position())).collect()
+    }
+
+    pub fn source_path(&self) -> Option<&Path> {
+        self.current().path()
+    }
+
+    pub fn source_position(&self) -> Position {
+        self.current().position()
+    }
+
+    pub fn set_buffer_size(&mut self, size: usize) {
+        for stream in self.stack.iter_mut() {
+            stream.set_buffer_size(size);
+        }
+        self.buffer_size = Some(size);
+    }
+
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        for stream in self.stack.iter_mut() {
+            stream.set_line_endings(endings);
+        }
+        self.line_endings = Some(endings);
+    }
+
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        for stream in self.stack.iter_mut() {
+            stream.set_decode_mode(mode);
+        }
+        self.decode_mode = Some(mode);
+    }
+
+    pub fn replacements(&self) -> usize {
+        self.replacements + self.stack.iter().map(|stream| stream.replacements()).sum::<usize>()
+    }
+
+    pub fn skip_malformed(&mut self) -> bool {
+        let skipped = self.current_mut().skip_malformed();
+        if skipped {
+            self.error = None;
+        }
+        skipped
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_next() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_peek() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    ///
+    /// after an error is returned, the rest of the stream which caused it is skipped.
+    ///
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        let result = self.try_peek()?;
+        if result.is_some() {
+            self.current_mut().next();
+        }
+        Ok(result)
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        loop {
+            if let Some(c) = self.current_mut().try_peek()? {
+                return Ok(Some(c));
+            }
+            // 一番外側のストリームは、位置を返せるように残しておく。
+            if self.stack.len() == 1 {
+                return Ok(None);
+            }
+            if let Some(stream) = self.stack.pop() {
+                self.replacements += stream.replacements();
+            }
+            self.canonical.pop();
+        }
+    }
+
+    // 相対パスは、読んでいるファイルのディレクトリから探す。
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if path.is_relative() {
+            let dir = self.stack.iter().rev()
+                .filter_map(|stream| stream.path())
+                .find(|path| path != &Path::new("-"))
+                .and_then(|path| path.parent());
+            if let Some(dir) = dir {
+                return dir.join(path);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    fn current(&self) -> &CharStream<'a> {
+        &self.stack[self.stack.len() - 1]
+    }
+
+    fn current_mut(&mut self) -> &mut CharStream<'a> {
+        let last = self.stack.len() - 1;
+        &mut self.stack[last]
+    }
+}
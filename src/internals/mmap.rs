@@ -0,0 +1,412 @@
+use std::cell::RefCell;
+use std::char;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::str;
+use memmap2::Mmap;
+use encoding::DecodeMode;
+use internals::decode_utf8;
+use {Error, LineEndings, Position};
+
+// interval in bytes of positions remembered to count the position of a byte offset.
+const CHECKPOINT_INTERVAL: usize = 4 * 1024;
+
+///
+/// reads chars from a memory-mapped UTF-8 file without copying it.
+///
+/// The bytes are decoded as they are read, and invalid UTF-8 is handled by `mode`.
+/// Positions of byte offsets are counted from checkpoints,
+/// which are made only up to the furthest offset asked for.
+/// Byte offsets are offsets in the file, which include invalid bytes.
+///
+#[derive(Debug)]
+pub struct InternalMmap {
+    mmap: Mmap,
+    index: usize,
+    back: usize,
+    front_chars: VecDeque<char>,
+    back_chars: VecDeque<char>,
+    remaining: usize,
+    error: Option<Error>,
+    malformed: bool,
+    mode: DecodeMode,
+    // 不正なバイト列の位置と長さ。U+FFFD に置き換えたものと、読み飛ばしたもの。
+    replaced: BTreeMap<usize, usize>,
+    skipped: BTreeMap<usize, usize>,
+    replacements: usize,
+    endings: LineEndings,
+    // 位置と、その直前の文字が '\r' かどうか。
+    checkpoints: RefCell<Vec<(Position, bool)>>,
+}
+
+impl InternalMmap {
+    pub fn map(file: &File) -> Result<InternalMmap, Error> {
+        // マップしている間にファイルを変更しないことは、CharStream::from_path_mmap の利用者が守る。
+        let mmap = unsafe { Mmap::map(file)? };
+        let back = mmap.len();
+        Ok(InternalMmap {
+            mmap,
+            index: 0,
+            back,
+            front_chars: VecDeque::new(),
+            back_chars: VecDeque::new(),
+            remaining: 0,
+            error: None,
+            malformed: false,
+            mode: DecodeMode::Strict,
+            replaced: BTreeMap::new(),
+            skipped: BTreeMap::new(),
+            replacements: 0,
+            endings: LineEndings::default(),
+            checkpoints: RefCell::new(vec![(Position::new(), false)]),
+        })
+    }
+
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        if self.endings != endings {
+            self.endings = endings;
+            self.checkpoints = RefCell::new(vec![(Position::new(), false)]);
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: DecodeMode) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.checkpoints = RefCell::new(vec![(Position::new(), false)]);
+        }
+    }
+
+    ///
+    /// count of malformed byte sequences which were replaced or skipped by the mode.
+    ///
+    pub fn replacements(&self) -> usize {
+        self.replacements
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        if let Some(c) = self.front_chars.pop_front() {
+            return self.consumed(Some(c));
+        }
+        let (c, len) = self.decode_front()?;
+        if self.index < self.back {
+            self.index += len;
+        }else{
+            self.back_chars.pop_front();
+        }
+        self.consumed(Some(c))
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        if let Some(&c) = self.front_chars.front() {
+            return Some(c);
+        }
+        self.decode_front().map(|(c, _)| c)
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        match self.next() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        match self.peek() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    fn take_error(&mut self) -> Result<Option<char>, Error> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// the next char and the count of bytes of it.
+    ///  invalid bytes are replaced or skipped by the mode, or end the stream with an error.
+    ///
+    fn decode_front(&mut self) -> Option<(char, usize)> {
+        if self.malformed {
+            return None;
+        }
+        loop {
+            if self.index >= self.back {
+                return self.back_chars.front().map(|&c| (c, 0));
+            }
+            let len = match first_char(&self.mmap[self.index..self.back]) {
+                Ok(c) => return c.map(|c| (c, c.len_utf8())),
+                Err(len) => len,
+            };
+            match self.mode {
+                DecodeMode::Strict => {
+                    self.error = Some(self.invalid(self.index, len));
+                    self.malformed = true;
+                    return None;
+                },
+                DecodeMode::Lossy => {
+                    if self.replaced.insert(self.index, len).is_none() {
+                        self.replacements += 1;
+                    }
+                    return Some((char::REPLACEMENT_CHARACTER, len));
+                },
+                DecodeMode::Skip => {
+                    if self.skipped.insert(self.index, len).is_none() {
+                        self.replacements += 1;
+                    }
+                    self.index += len;
+                },
+            }
+        }
+    }
+
+    ///
+    /// skip the invalid bytes which ended the stream, and continue reading after them.
+    /// returns false if the stream was not ended by invalid bytes.
+    ///
+    pub fn skip_malformed(&mut self) -> bool {
+        if !self.malformed {
+            return false;
+        }
+        if let Err(len) = first_char(&self.mmap[self.index..self.back]) {
+            self.skipped.insert(self.index, len);
+            self.index += len;
+        }
+        self.malformed = false;
+        self.error = None;
+        true
+    }
+
+    ///
+    /// decode the rest of the file once, and count the chars which `len` returns, before it is read from both ends.
+    ///  invalid UTF-8 is an error in `DecodeMode::Strict`.
+    ///
+    pub fn count(&mut self) -> Result<(), Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        // 不正なバイトで止まったストリームは、その先を読まない。
+        if self.malformed {
+            self.back = self.index;
+        }
+
+        let mut count = self.front_chars.len() + self.back_chars.len();
+        let mut start = self.index;
+        while start < self.back {
+            let (valid, invalid) = split_valid(&self.mmap[start..self.back]);
+            count += valid.chars().count();
+            start += valid.len();
+            if let Some(len) = invalid {
+                match self.mode {
+                    DecodeMode::Strict => return Err(self.invalid(start, len)),
+                    DecodeMode::Lossy => count += 1,
+                    DecodeMode::Skip => {},
+                }
+                start += len;
+            }
+        }
+        self.remaining = count;
+        Ok(())
+    }
+
+    pub fn next_back(&mut self) -> Option<char> {
+        while self.back_chars.is_empty() && self.index < self.back {
+            self.read_back();
+        }
+        let result = match self.back_chars.pop_back() {
+            Some(c) => Some(c),
+            None => self.front_chars.pop_back(),
+        };
+        self.consumed(result)
+    }
+
+    // 末尾 4 バイト以内の継続バイトでないところから復号すれば、前から復号した場合と同じ文字になる。
+    fn read_back(&mut self) {
+        let low = self.index.max(self.back.saturating_sub(4));
+        let start = match (low..self.back).rev().find(|&i| !is_continuation(self.mmap[i])) {
+            Some(start) => start,
+            None if low == self.index => low,
+            None => self.back - 1,
+        };
+        // DecodeMode::Strict では、count で検査してあるので不正なバイトはない。
+        let mut chars = Vec::new();
+        decode_utf8(&self.mmap[start..self.back], self.mode, &mut chars);
+        self.back_chars.extend(chars);
+        self.back = start;
+    }
+
+    ///
+    /// count of chars not read yet, which is counted by `count`.
+    ///
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    ///
+    /// move to the byte offset, which must be on a char boundary.
+    ///  the position of the byte offset is not counted here. see `position_at`.
+    ///  returns whether the previous char is '\r'.
+    ///
+    pub fn seek(&mut self, byte_offset: usize) -> Result<bool, Error> {
+        let len = self.mmap.len();
+        if byte_offset > len || (byte_offset < len && is_continuation(self.mmap[byte_offset])) {
+            let err = io::Error::new(ErrorKind::InvalidInput, format!("byte offset {} is not on a char boundary", byte_offset));
+            return Err(Error::Io(err));
+        }
+
+        self.set_index(byte_offset);
+        Ok(byte_offset > 0 && self.mmap[byte_offset - 1] == b'\r')
+    }
+
+    ///
+    /// byte offset of the next char.
+    ///
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    ///
+    /// move to the byte offset which is known to be on a char boundary.
+    ///
+    pub fn set_index(&mut self, byte_offset: usize) {
+        self.index = byte_offset;
+        self.back = self.mmap.len();
+        self.front_chars.clear();
+        self.back_chars.clear();
+        self.error = None;
+        self.malformed = false;
+    }
+
+    ///
+    /// chars which are read before the rest of the file.
+    ///
+    pub fn push_front(&mut self, chars: Vec<char>) {
+        for c in chars.into_iter().rev() {
+            self.front_chars.push_front(c);
+        }
+    }
+
+    ///
+    /// count of bytes in the file from the byte offset to the end of `c`, which was read there.
+    ///  it includes the invalid bytes which were skipped before `c`, or which `c` replaced.
+    ///
+    pub fn byte_len(&self, byte_offset: usize, c: char) -> usize {
+        let mut len = 0;
+        while let Some(&skipped) = self.skipped.get(&(byte_offset + len)) {
+            len += skipped;
+        }
+        match self.replaced.get(&(byte_offset + len)) {
+            Some(&replaced) if c == char::REPLACEMENT_CHARACTER => len + replaced,
+            _ => len + c.len_utf8(),
+        }
+    }
+
+    ///
+    /// count of bytes in the file of `c`, which ends at the byte offset.
+    ///
+    pub fn byte_len_back(&self, byte_offset: usize, c: char) -> usize {
+        if c == char::REPLACEMENT_CHARACTER {
+            if let Some((&start, &len)) = self.replaced.range(..byte_offset).next_back() {
+                if start + len == byte_offset {
+                    return len;
+                }
+            }
+        }
+        c.len_utf8()
+    }
+
+    ///
+    /// position of the byte offset, counted from the nearest checkpoint before it.
+    ///  invalid bytes are counted as the mode reads them.
+    ///
+    pub fn position_at(&self, byte_offset: usize) -> Position {
+        let byte_offset = byte_offset.min(self.mmap.len());
+        let mut checkpoints = self.checkpoints.borrow_mut();
+
+        // 求めるバイト位置の手前まで、チェックポイントを延ばす。
+        loop {
+            let (position, after_cr) = checkpoints[checkpoints.len() - 1];
+            if position.byte_offset + CHECKPOINT_INTERVAL > byte_offset {
+                break;
+            }
+            let mut end = position.byte_offset + CHECKPOINT_INTERVAL;
+            while end < self.mmap.len() && is_continuation(self.mmap[end]) {
+                end += 1;
+            }
+            checkpoints.push(self.advance_to(position, after_cr, end));
+        }
+
+        let index = match checkpoints.binary_search_by_key(&byte_offset, |&(position, _)| position.byte_offset) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        let (position, after_cr) = checkpoints[index];
+        self.advance_to(position, after_cr, byte_offset).0
+    }
+
+    fn advance_to(&self, mut position: Position, mut after_cr: bool, end: usize) -> (Position, bool) {
+        let mut start = position.byte_offset;
+        while start < end {
+            let (valid, invalid) = split_valid(&self.mmap[start..end]);
+            for c in valid.chars() {
+                position.advance(c, self.endings.line_break(c, after_cr));
+                after_cr = c == '\r';
+            }
+            start += valid.len();
+            if let Some(len) = invalid {
+                // 読み飛ばしたバイトは、前後の "\r" と "\n" を分けない。
+                if self.mode == DecodeMode::Lossy {
+                    let c = char::REPLACEMENT_CHARACTER;
+                    position.advance(c, self.endings.line_break(c, after_cr));
+                    after_cr = false;
+                }
+                start += len;
+                position.byte_offset = start;
+            }
+        }
+        (position, after_cr)
+    }
+
+    fn invalid(&self, offset: usize, len: usize) -> Error {
+        Error::InvalidUtf8 {
+            offset,
+            line: self.position_at(offset).line,
+            bytes: self.mmap[offset..offset + len].to_vec(),
+        }
+    }
+
+    fn consumed(&mut self, result: Option<char>) -> Option<char> {
+        if result.is_some() {
+            self.remaining = self.remaining.saturating_sub(1);
+        }
+        result
+    }
+}
+
+// 先頭の文字を返す。正しい UTF-8 でなければ、不正なバイトの長さを返す。
+fn first_char(bytes: &[u8]) -> Result<Option<char>, usize> {
+    let window = &bytes[..bytes.len().min(4)];
+    match split_valid(window) {
+        (valid, _) if !valid.is_empty() => Ok(valid.chars().next()),
+        (_, Some(len)) => Err(len),
+        (_, None) => Ok(None),
+    }
+}
+
+// 先頭から正しい UTF-8 の部分と、その後ろの不正なバイトの長さに分ける。
+fn split_valid(bytes: &[u8]) -> (&str, Option<usize>) {
+    match str::from_utf8(bytes) {
+        Ok(s) => (s, None),
+        Err(err) => {
+            let valid = str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default();
+            (valid, Some(err.error_len().unwrap_or(bytes.len() - valid.len())))
+        },
+    }
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
@@ -1,7 +1,25 @@
+pub use self::borrowed_str::*;
+pub use self::chain::*;
 pub use self::char_vec::*;
 pub use self::file::*;
+pub use self::follow::*;
+pub use self::include::*;
+pub use self::mmap::*;
+pub use self::reader::*;
+pub use self::reverse_file::*;
+pub use self::splice::*;
 pub use self::stdin::*;
+pub use self::stream::*;
 
+mod borrowed_str;
+mod chain;
 mod char_vec;
 mod file;
-mod stdin;
\ No newline at end of file
+mod follow;
+mod include;
+mod mmap;
+mod reader;
+mod reverse_file;
+mod splice;
+mod stdin;
+mod stream;
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use encoding::{Decoder, Encoding};
+use internals::InternalCharVec;
+use {CharSource, Error};
+
+///
+/// default count of bytes which are read and decoded at once.
+///
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+pub struct InternalReader<R> {
+    reader: R,
+    bytes: Vec<u8>,
+    buf: Option<InternalCharVec>,
+    is_eof: bool,
+    error: Option<Error>,
+    malformed: bool,
+    count: usize,
+    decoder: Decoder,
+}
+
+impl<R: Read> InternalReader<R> {
+    pub fn new(reader: R) -> InternalReader<R> {
+        InternalReader::with_decoder(reader, Decoder::new(Encoding::Utf8))
+    }
+
+    pub fn with_decoder(reader: R, decoder: Decoder) -> InternalReader<R> {
+        InternalReader {
+            reader,
+            bytes: vec![0; DEFAULT_BUFFER_SIZE],
+            buf: None,
+            is_eof: false,
+            error: None,
+            malformed: false,
+            count: 0,
+            decoder,
+        }
+    }
+
+    ///
+    /// whether the rest of the reader is UTF-8.
+    ///
+    pub fn is_utf8(&self) -> bool {
+        self.decoder.is_utf8()
+    }
+
+    ///
+    /// change count of bytes which are read and decoded at once.
+    ///
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.bytes = vec![0; size.max(1)];
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut Decoder {
+        &mut self.decoder
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        let result = self.peek();
+        if result.is_some() {
+            if let Some(ref mut char_vec) = self.buf {
+                char_vec.next();
+            }
+            self.count += 1;
+        }
+
+        result
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        loop {
+            if let Some(ref mut char_vec) = self.buf {
+                if let Some(c) = char_vec.peek() {
+                    return Some(c);
+                }
+            }
+
+            // 次に読める文字がない場合、次のチャンクを読み込む。
+            if self.is_eof {
+                return None;
+            }
+            self.read_next_chunk();
+        }
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        match self.next() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        match self.peek() {
+            Some(c) => Ok(Some(c)),
+            None => self.take_error(),
+        }
+    }
+
+    fn take_error(&mut self) -> Result<Option<char>, Error> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    fn read_next_chunk(&mut self){
+        match self.reader.read(&mut self.bytes) {
+            Ok(size) => {
+                // チャンクの境目で切れた文字は、次のチャンクまで Decoder に残る。
+                let mut chars = Vec::with_capacity(size);
+                if let Some(err) = self.decoder.decode(&self.bytes[..size], &mut chars, size == 0) {
+                    self.error = Some(err);
+                    self.malformed = true;
+                    self.is_eof = true;
+                }
+                if size == 0 {
+                    self.is_eof = true;
+                }
+                self.buf = Some(InternalCharVec::new(chars));
+            },
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(err) => {
+                self.error = Some(Error::Io(err));
+                self.is_eof = true;
+            },
+        }
+    }
+
+    ///
+    /// skip the malformed bytes which ended the stream, and continue reading after them.
+    /// returns false if the stream was not ended by malformed bytes.
+    ///
+    pub fn skip_malformed(&mut self) -> bool {
+        if !self.malformed {
+            return false;
+        }
+        self.malformed = false;
+        self.error = None;
+        self.is_eof = false;
+        true
+    }
+
+    ///
+    /// take out the reader and chars which were read from it but not consumed yet.
+    ///
+    pub fn into_inner(mut self) -> Result<(R, Vec<char>), Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        let mut chars = Vec::new();
+        if let Some(mut char_vec) = self.buf {
+            while let Some(c) = char_vec.next() {
+                chars.push(c);
+            }
+        }
+        Ok((self.reader, chars))
+    }
+
+    pub fn read_and_get_all_chars(mut self) -> Result<Vec<char>, Error> {
+        let mut chars = Vec::new();
+        while let Some(c) = self.try_next()? {
+            chars.push(c);
+        }
+        Ok(chars)
+    }
+}
+
+impl<R: Read> CharSource for InternalReader<R> {
+    fn next(&mut self) -> Option<char> {
+        InternalReader::next(self)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        InternalReader::peek(self)
+    }
+
+    fn position(&self) -> usize {
+        self.count
+    }
+
+    fn try_next(&mut self) -> Result<Option<char>, Error> {
+        InternalReader::try_next(self)
+    }
+
+    fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        InternalReader::try_peek(self)
+    }
+}
+
+impl<R> fmt::Debug for InternalReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InternalReader")
+            .field("buffer_size", &self.bytes.len())
+            .field("buf", &self.buf)
+            .field("is_eof", &self.is_eof)
+            .field("error", &self.error)
+            .field("malformed", &self.malformed)
+            .field("count", &self.count)
+            .field("decoder", &self.decoder)
+            .finish()
+    }
+}
@@ -0,0 +1,276 @@
+use std::char;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::str;
+use encoding::DecodeMode;
+use {Error, LineEndings, Position};
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+///
+/// reads chars of a file from both ends by blocks.
+///
+/// Bytes between `front` and `back` are not decoded yet.
+/// Both of them are always on a boundary where UTF-8 decoding can start,
+/// so decoding a block from either end gives the same chars as decoding the whole file.
+/// Invalid sequences are handled by `mode`, and an error ends the reading.
+///
+#[derive(Debug)]
+pub struct InternalReverseFile {
+    file: File,
+    front: u64,
+    back: u64,
+    front_chars: VecDeque<char>,
+    back_chars: VecDeque<char>,
+    remaining: usize,
+    block_size: usize,
+    mode: DecodeMode,
+    error: Option<Error>,
+}
+
+// invalid UTF-8 found by `DecodeMode::Strict`.
+struct Invalid {
+    offset: u64,
+    bytes: Vec<u8>,
+}
+
+impl InternalReverseFile {
+    ///
+    /// `chars` are already decoded chars before `front`, which are read first.
+    ///
+    pub fn new(file: File, front: u64, chars: Vec<char>) -> io::Result<InternalReverseFile> {
+        InternalReverseFile::with_block_size(file, front, chars, BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(file: File, front: u64, chars: Vec<char>, block_size: usize) -> io::Result<InternalReverseFile> {
+        let back = file.metadata()?.len();
+        Ok(InternalReverseFile {
+            file,
+            front: front.min(back),
+            back,
+            remaining: chars.len(),
+            front_chars: chars.into_iter().collect(),
+            back_chars: VecDeque::new(),
+            block_size: block_size.max(8),
+            mode: DecodeMode::Lossy,
+            error: None,
+        })
+    }
+
+    pub fn set_mode(&mut self, mode: DecodeMode) {
+        self.mode = mode;
+    }
+
+    ///
+    /// decode the bytes not decoded yet once, and count the chars which `len` returns.
+    ///  this must be called before reading.
+    ///  invalid UTF-8 is an error in `DecodeMode::Strict`.
+    ///  `position` and `after_cr` are the state before the first char not read yet,
+    ///  which give the line of the error.
+    ///
+    pub fn count(&mut self, mut position: Position, endings: LineEndings, mut after_cr: bool) -> Result<(), Error> {
+        let mut advance = |chars: &mut dyn Iterator<Item = char>| {
+            for c in chars {
+                position.advance(c, endings.line_break(c, after_cr));
+                after_cr = c == '\r';
+            }
+            position.line
+        };
+
+        let mut count = self.front_chars.len() + self.back_chars.len();
+        advance(&mut self.front_chars.iter().cloned());
+        let mut start = self.front;
+        while start < self.back {
+            let (chars, end, invalid) = self.decode_forward(start)?;
+            count += chars.len();
+            let line = advance(&mut chars.into_iter());
+            if let Some(invalid) = invalid {
+                return Err(Error::InvalidUtf8 { offset: invalid.offset as usize, line, bytes: invalid.bytes });
+            }
+            start = end;
+        }
+        self.remaining = count;
+        Ok(())
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        if self.front_chars.is_empty() && self.front < self.back {
+            self.read_front_block();
+        }
+
+        let result = match self.front_chars.pop_front() {
+            Some(c) => Some(c),
+            None => self.back_chars.pop_front(),
+        };
+        self.consumed(result)
+    }
+
+    pub fn next_back(&mut self) -> Option<char> {
+        if self.back_chars.is_empty() && self.front < self.back {
+            self.read_back_block();
+        }
+
+        let result = match self.back_chars.pop_back() {
+            Some(c) => Some(c),
+            None => self.front_chars.pop_back(),
+        };
+        self.consumed(result)
+    }
+
+    ///
+    /// take the error which ended the reading.
+    ///
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    ///
+    /// count of chars not read yet, which is counted by `count`.
+    ///
+    pub fn len(&self) -> usize {
+        self.remaining
+    }
+
+    fn consumed(&mut self, result: Option<char>) -> Option<char> {
+        if result.is_some() {
+            self.remaining -= 1;
+        }
+        result
+    }
+
+    fn read_front_block(&mut self) {
+        match self.decode_forward(self.front) {
+            Ok((chars, end, invalid)) => {
+                self.front_chars.extend(chars);
+                self.front = end;
+                if invalid.is_some() {
+                    self.give_up(modified());
+                }
+            },
+            Err(err) => self.give_up(err),
+        }
+    }
+
+    fn read_back_block(&mut self) {
+        let start = if self.back - self.front > self.block_size as u64 {
+            self.back - self.block_size as u64
+        }else{
+            self.front
+        };
+
+        let bytes = match self.read_bytes(start, self.back) {
+            Ok(bytes) => bytes,
+            Err(err) => return self.give_up(err),
+        };
+        // start から 3 バイト以内に、UTF-8 の復号を始められる位置がある。
+        let mut split = 0;
+        if start > self.front {
+            split = 3.min(bytes.len());
+            for (i, &b) in bytes.iter().enumerate().take(4) {
+                if !is_continuation(b) {
+                    split = i;
+                    break;
+                }
+            }
+        }
+
+        let mut chars = Vec::new();
+        if decode_utf8(&bytes[split..], self.mode, &mut chars).is_some() {
+            // 検査した後でファイルが書き換えられた。
+            return self.give_up(modified());
+        }
+        for c in chars.into_iter().rev() {
+            self.back_chars.push_front(c);
+        }
+        self.back = start + split as u64;
+    }
+
+    ///
+    /// decode a block starting at `start`.
+    /// returns decoded chars, the end of decoded bytes, and invalid bytes which stopped decoding in `DecodeMode::Strict`.
+    ///
+    fn decode_forward(&self, start: u64) -> io::Result<(Vec<char>, u64, Option<Invalid>)> {
+        let end = if self.back - start > self.block_size as u64 {
+            start + self.block_size as u64
+        }else{
+            self.back
+        };
+
+        let bytes = self.read_bytes(start, end)?;
+        let mut split = bytes.len();
+        if end < self.back {
+            // 末尾 3 バイト以内の先頭バイトから次のブロックで読み直す。
+            for i in (bytes.len() - 3..bytes.len()).rev() {
+                if !is_continuation(bytes[i]) {
+                    split = i;
+                    break;
+                }
+            }
+        }
+
+        let mut chars = Vec::new();
+        match decode_utf8(&bytes[..split], self.mode, &mut chars) {
+            Some((offset, len)) => {
+                let invalid = Invalid { offset: start + offset as u64, bytes: bytes[offset..offset + len].to_vec() };
+                Ok((chars, start + offset as u64, Some(invalid)))
+            },
+            None => Ok((chars, start + split as u64, None)),
+        }
+    }
+
+    fn read_bytes(&self, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut bytes = Vec::with_capacity((end - start) as usize);
+        file.take(end - start).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < end - start {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file was truncated."));
+        }
+        Ok(bytes)
+    }
+
+    // 読めなくなった場合は、エラーを残して残りを読み捨てる。
+    fn give_up(&mut self, err: io::Error) {
+        self.front = self.back;
+        self.remaining = self.front_chars.len() + self.back_chars.len();
+        self.error = Some(Error::Io(err));
+    }
+}
+
+fn modified() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "file was modified while reading.")
+}
+
+///
+/// decode UTF-8 bytes and push chars to `chars`, handling invalid sequences by `mode`.
+///  returns the offset and the length of the invalid sequence which stopped decoding in `DecodeMode::Strict`.
+///
+pub fn decode_utf8(bytes: &[u8], mode: DecodeMode, chars: &mut Vec<char>) -> Option<(usize, usize)> {
+    let mut start = 0;
+    loop {
+        let (valid, invalid) = match str::from_utf8(&bytes[start..]) {
+            Ok(s) => (s, None),
+            Err(err) => {
+                let valid = &bytes[start..start + err.valid_up_to()];
+                let len = err.error_len().unwrap_or(bytes.len() - start - valid.len());
+                (str::from_utf8(valid).unwrap_or_default(), Some(len))
+            },
+        };
+        chars.extend(valid.chars());
+        start += valid.len();
+
+        let len = invalid?;
+        match mode {
+            DecodeMode::Strict => return Some((start, len)),
+            DecodeMode::Lossy => chars.push(char::REPLACEMENT_CHARACTER),
+            DecodeMode::Skip => {},
+        }
+        start += len;
+    }
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
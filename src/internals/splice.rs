@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use encoding::DecodeMode;
+use {CharStream, Error, LineEndings, Origin, Position};
+
+///
+/// reads chars spliced into a stream before the rest of it.
+///
+/// The first stream is the original input, and the others are expansions nested in order.
+///
+#[derive(Debug)]
+pub struct InternalSplice<'a> {
+    stack: Vec<CharStream<'a>>,
+    limit: usize,
+    depth: usize,
+    error: Option<Error>,
+    replacements: usize,
+}
+
+impl<'a> InternalSplice<'a> {
+    pub fn new(original: CharStream<'a>, limit: usize) -> InternalSplice<'a> {
+        InternalSplice {
+            stack: vec![original],
+            limit,
+            depth: 0,
+            error: None,
+            replacements: 0,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    ///
+    /// read the stream before the rest.
+    ///  `pending` are chars pushed back to the stream, which are read after the spliced one.
+    ///
+    pub fn splice(&mut self, stream: CharStream<'a>, pending: &mut VecDeque<char>) -> Result<(), Error> {
+        if self.stack.len() > self.limit {
+            return Err(Error::SpliceLimit { limit: self.limit });
+        }
+
+        let current = self.current_mut();
+        while let Some(c) = pending.pop_back() {
+            current.unread(c);
+        }
+        self.stack.push(stream);
+        Ok(())
+    }
+
+    ///
+    /// origin of the char read last.
+    ///
+    pub fn origin(&self) -> Origin {
+        match self.depth {
+            0 => Origin::Original,
+            depth => Origin::Expansion { depth },
+        }
+    }
+
+    pub fn original_path(&self) -> Option<&Path> {
+        self.stack[0].path()
+    }
+
+    pub fn original_position(&self) -> Position {
+        self.stack[0].position()
+    }
+
+    pub fn set_buffer_size(&mut self, size: usize) {
+        for stream in self.stack.iter_mut() {
+            stream.set_buffer_size(size);
+        }
+    }
+
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        for stream in self.stack.iter_mut() {
+            stream.set_line_endings(endings);
+        }
+    }
+
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        for stream in self.stack.iter_mut() {
+            stream.set_decode_mode(mode);
+        }
+    }
+
+    pub fn replacements(&self) -> usize {
+        self.replacements + self.stack.iter().map(|stream| stream.replacements()).sum::<usize>()
+    }
+
+    pub fn skip_malformed(&mut self) -> bool {
+        let skipped = self.current_mut().skip_malformed();
+        if skipped {
+            self.error = None;
+        }
+        skipped
+    }
+
+    pub fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_next() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.try_peek() {
+            Ok(result) => result,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            },
+        }
+    }
+
+    ///
+    /// after an error is returned, the rest of the stream which caused it is skipped.
+    ///
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        let (result, index) = self.find()?;
+        if result.is_some() {
+            self.stack[index].next();
+            // 読み終えた展開は、その後の文字を読んだときに取り除く。
+            while self.stack.len() > index + 1 {
+                self.pop();
+            }
+            self.depth = index;
+        }
+        Ok(result)
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        let (result, _) = self.find()?;
+        Ok(result)
+    }
+
+    // 次の文字と、それを読むストリームの位置を探す。
+    fn find(&mut self) -> Result<(Option<char>, usize), Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        for index in (0..self.stack.len()).rev() {
+            if let Some(c) = self.stack[index].try_peek()? {
+                return Ok((Some(c), index));
+            }
+        }
+        Ok((None, 0))
+    }
+
+    fn pop(&mut self) {
+        if let Some(stream) = self.stack.pop() {
+            self.replacements += stream.replacements();
+        }
+    }
+
+    fn current_mut(&mut self) -> &mut CharStream<'a> {
+        let last = self.stack.len() - 1;
+        &mut self.stack[last]
+    }
+}
@@ -0,0 +1,375 @@
+use std::io::BufRead;
+use std::path::Path;
+use internals::{InternalChain, InternalCharVec, InternalMmap, InternalStr, InternalFile, InternalFollow, InternalInclude, InternalReader, InternalSplice, InternalStdin};
+use encoding::{DecodeMode, Decoder};
+use {CharSource, Error, LineEndings, Position};
+
+#[derive(Debug)]
+pub enum InternalStream<'a> {
+    Chars { chars: InternalCharVec },
+    Str { chars: InternalStr<'a> },
+    File { file: InternalFile },
+    StdIn { stdin: InternalStdin },
+    Reader { reader: InternalReader<Box<dyn BufRead + Send + 'a>> },
+    Source { source: Box<dyn CharSource + Send + 'a> },
+    Follow { follow: InternalFollow },
+    Mmap { mmap: InternalMmap },
+    Chain { chain: InternalChain<'a> },
+    Include { include: InternalInclude<'a> },
+    Splice { splice: InternalSplice<'a> },
+}
+
+impl<'a> InternalStream<'a> {
+    pub fn next(&mut self) -> Option<char> {
+        match *self {
+            InternalStream::Chars { ref mut chars } => {
+                chars.next()
+            },
+            InternalStream::Str { ref mut chars } => {
+                chars.next()
+            },
+            InternalStream::File { ref mut file } => {
+                file.next()
+            },
+            InternalStream::StdIn { ref mut stdin } => {
+                stdin.next()
+            },
+            InternalStream::Reader { ref mut reader } => {
+                reader.next()
+            },
+            InternalStream::Source { ref mut source } => {
+                source.next()
+            },
+            InternalStream::Follow { ref mut follow } => {
+                follow.next()
+            },
+            InternalStream::Mmap { ref mut mmap } => {
+                mmap.next()
+            },
+            InternalStream::Chain { ref mut chain } => {
+                chain.next()
+            },
+            InternalStream::Include { ref mut include } => {
+                include.next()
+            },
+            InternalStream::Splice { ref mut splice } => {
+                splice.next()
+            },
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<char> {
+        match *self {
+            InternalStream::Chars { ref mut chars } => {
+                chars.peek()
+            },
+            InternalStream::Str { ref mut chars } => {
+                chars.peek()
+            },
+            InternalStream::File { ref mut file } => {
+                file.peek()
+            },
+            InternalStream::StdIn { ref mut stdin } => {
+                stdin.peek()
+            },
+            InternalStream::Reader { ref mut reader } => {
+                reader.peek()
+            },
+            InternalStream::Source { ref mut source } => {
+                source.peek()
+            },
+            InternalStream::Follow { ref mut follow } => {
+                follow.peek()
+            },
+            InternalStream::Mmap { ref mut mmap } => {
+                mmap.peek()
+            },
+            InternalStream::Chain { ref mut chain } => {
+                chain.peek()
+            },
+            InternalStream::Include { ref mut include } => {
+                include.peek()
+            },
+            InternalStream::Splice { ref mut splice } => {
+                splice.peek()
+            },
+        }
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        match *self {
+            InternalStream::Chars { ref mut chars } => {
+                Ok(chars.next())
+            },
+            InternalStream::Str { ref mut chars } => {
+                Ok(chars.next())
+            },
+            InternalStream::File { ref mut file } => {
+                file.try_next()
+            },
+            InternalStream::StdIn { ref mut stdin } => {
+                stdin.try_next()
+            },
+            InternalStream::Reader { ref mut reader } => {
+                reader.try_next()
+            },
+            InternalStream::Source { ref mut source } => {
+                source.try_next()
+            },
+            InternalStream::Follow { ref mut follow } => {
+                follow.try_next()
+            },
+            InternalStream::Mmap { ref mut mmap } => {
+                mmap.try_next()
+            },
+            InternalStream::Chain { ref mut chain } => {
+                chain.try_next()
+            },
+            InternalStream::Include { ref mut include } => {
+                include.try_next()
+            },
+            InternalStream::Splice { ref mut splice } => {
+                splice.try_next()
+            },
+        }
+    }
+
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        match *self {
+            InternalStream::Chars { ref mut chars } => {
+                Ok(chars.peek())
+            },
+            InternalStream::Str { ref mut chars } => {
+                Ok(chars.peek())
+            },
+            InternalStream::File { ref mut file } => {
+                file.try_peek()
+            },
+            InternalStream::StdIn { ref mut stdin } => {
+                stdin.try_peek()
+            },
+            InternalStream::Reader { ref mut reader } => {
+                reader.try_peek()
+            },
+            InternalStream::Source { ref mut source } => {
+                source.try_peek()
+            },
+            InternalStream::Follow { ref mut follow } => {
+                follow.try_peek()
+            },
+            InternalStream::Mmap { ref mut mmap } => {
+                mmap.try_peek()
+            },
+            InternalStream::Chain { ref mut chain } => {
+                chain.try_peek()
+            },
+            InternalStream::Include { ref mut include } => {
+                include.try_peek()
+            },
+            InternalStream::Splice { ref mut splice } => {
+                splice.try_peek()
+            },
+        }
+    }
+
+    pub fn set_buffer_size(&mut self, size: usize) {
+        match *self {
+            InternalStream::File { ref mut file } => file.set_buffer_size(size),
+            InternalStream::StdIn { ref mut stdin } => stdin.set_buffer_size(size),
+            InternalStream::Reader { ref mut reader } => reader.set_buffer_size(size),
+            InternalStream::Follow { ref mut follow } => follow.set_buffer_size(size),
+            InternalStream::Chain { ref mut chain } => chain.set_buffer_size(size),
+            InternalStream::Include { ref mut include } => include.set_buffer_size(size),
+            InternalStream::Splice { ref mut splice } => splice.set_buffer_size(size),
+            InternalStream::Chars { .. } | InternalStream::Str { .. } | InternalStream::Source { .. } | InternalStream::Mmap { .. } => {},
+        }
+    }
+
+    pub fn skip_malformed(&mut self) -> bool {
+        match *self {
+            InternalStream::File { ref mut file } => file.skip_malformed(),
+            InternalStream::StdIn { ref mut stdin } => stdin.skip_malformed(),
+            InternalStream::Reader { ref mut reader } => reader.skip_malformed(),
+            InternalStream::Follow { ref mut follow } => follow.skip_malformed(),
+            InternalStream::Mmap { ref mut mmap } => mmap.skip_malformed(),
+            InternalStream::Chain { ref mut chain } => chain.skip_malformed(),
+            InternalStream::Include { ref mut include } => include.skip_malformed(),
+            InternalStream::Splice { ref mut splice } => splice.skip_malformed(),
+            InternalStream::Chars { .. } | InternalStream::Str { .. } | InternalStream::Source { .. } => false,
+        }
+    }
+
+    pub fn decoder(&self) -> Option<&Decoder> {
+        match *self {
+            InternalStream::File { ref file } => Some(file.decoder()),
+            InternalStream::StdIn { ref stdin } => Some(stdin.decoder()),
+            InternalStream::Reader { ref reader } => Some(reader.decoder()),
+            InternalStream::Follow { ref follow } => Some(follow.decoder()),
+            InternalStream::Chars { .. } | InternalStream::Str { .. } | InternalStream::Source { .. } | InternalStream::Mmap { .. } | InternalStream::Chain { .. } | InternalStream::Include { .. } | InternalStream::Splice { .. } => None,
+        }
+    }
+
+    pub fn decoder_mut(&mut self) -> Option<&mut Decoder> {
+        match *self {
+            InternalStream::File { ref mut file } => Some(file.decoder_mut()),
+            InternalStream::StdIn { ref mut stdin } => Some(stdin.decoder_mut()),
+            InternalStream::Reader { ref mut reader } => Some(reader.decoder_mut()),
+            InternalStream::Follow { ref mut follow } => Some(follow.decoder_mut()),
+            InternalStream::Chars { .. } | InternalStream::Str { .. } | InternalStream::Source { .. } | InternalStream::Mmap { .. } | InternalStream::Chain { .. } | InternalStream::Include { .. } | InternalStream::Splice { .. } => None,
+        }
+    }
+
+    ///
+    /// set how the streams which are read by this stream handle malformed byte sequences.
+    ///
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        match *self {
+            InternalStream::Mmap { ref mut mmap } => mmap.set_mode(mode),
+            InternalStream::Chain { ref mut chain } => chain.set_decode_mode(mode),
+            InternalStream::Include { ref mut include } => include.set_decode_mode(mode),
+            InternalStream::Splice { ref mut splice } => splice.set_decode_mode(mode),
+            _ => {
+                if let Some(decoder) = self.decoder_mut() {
+                    decoder.set_mode(mode);
+                }
+            },
+        }
+    }
+
+    ///
+    /// set the line endings of the streams which are read by this stream.
+    ///
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        match *self {
+            InternalStream::Chain { ref mut chain } => chain.set_line_endings(endings),
+            InternalStream::Include { ref mut include } => include.set_line_endings(endings),
+            InternalStream::Splice { ref mut splice } => splice.set_line_endings(endings),
+            InternalStream::Mmap { ref mut mmap } => mmap.set_line_endings(endings),
+            _ => {
+                if let Some(decoder) = self.decoder_mut() {
+                    decoder.set_line_endings(endings);
+                }
+            },
+        }
+    }
+
+    ///
+    /// count of malformed byte sequences which were replaced or skipped by the decoders.
+    ///
+    pub fn replacements(&self) -> usize {
+        match *self {
+            InternalStream::Chain { ref chain } => chain.replacements(),
+            InternalStream::Include { ref include } => include.replacements(),
+            InternalStream::Splice { ref splice } => splice.replacements(),
+            InternalStream::Mmap { ref mmap } => mmap.replacements(),
+            _ => self.decoder().map_or(0, |decoder| decoder.replacements()),
+        }
+    }
+
+    ///
+    /// whether the stream can move to any offset of its input, like a str or a memory-mapped file.
+    ///
+    pub fn is_seekable(&self) -> bool {
+        matches!(*self, InternalStream::Chars { .. } | InternalStream::Str { .. } | InternalStream::Mmap { .. })
+    }
+
+    ///
+    /// whether the stream reads chars from other CharStreams, which tell where the chars come from.
+    ///
+    pub fn is_stacked(&self) -> bool {
+        matches!(*self, InternalStream::Chain { .. } | InternalStream::Include { .. } | InternalStream::Splice { .. })
+    }
+
+    pub fn source_index(&self) -> Option<usize> {
+        match *self {
+            InternalStream::Chain { ref chain } => chain.source_index(),
+            _ => None,
+        }
+    }
+
+    pub fn source_path(&self) -> Option<&Path> {
+        match *self {
+            InternalStream::Chain { ref chain } => chain.source_path(),
+            InternalStream::Include { ref include } => include.source_path(),
+            InternalStream::Splice { ref splice } => splice.original_path(),
+            _ => None,
+        }
+    }
+
+    pub fn source_position(&self) -> Option<Position> {
+        match *self {
+            InternalStream::Chain { ref chain } => chain.source_position(),
+            InternalStream::Include { ref include } => Some(include.source_position()),
+            InternalStream::Splice { ref splice } => Some(splice.original_position()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// offset of the next char in the input, which can be given to `move_to`.
+    ///  returns None if the stream is not seekable.
+    ///
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            InternalStream::Chars { ref chars } => Some(chars.index()),
+            InternalStream::Str { ref chars } => Some(chars.index()),
+            InternalStream::Mmap { ref mmap } => Some(mmap.index()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// move to the offset returned by `offset`.
+    ///  returns false if the stream is not seekable.
+    ///
+    pub fn move_to(&mut self, offset: usize) -> bool {
+        match *self {
+            InternalStream::Chars { ref mut chars } => chars.set_index(offset),
+            InternalStream::Str { ref mut chars } => chars.set_index(offset),
+            InternalStream::Mmap { ref mut mmap } => mmap.set_index(offset),
+            _ => return false,
+        }
+        true
+    }
+
+    ///
+    /// move to the byte offset of the input, and returns whether the previous char is '\r'.
+    ///
+    pub fn seek(&mut self, byte_offset: usize) -> Result<bool, Error> {
+        match *self {
+            InternalStream::Mmap { ref mut mmap } => mmap.seek(byte_offset),
+            _ => Err(Error::UnsupportedOperation("can't seek CharStream which is not made by 'from_path_mmap'")),
+        }
+    }
+
+    ///
+    /// count of bytes in the input from the byte offset to the end of `c`, which was read there.
+    ///  it differs from the length of `c` in UTF-8 only for a memory-mapped file with invalid bytes.
+    ///
+    pub fn byte_len(&self, byte_offset: usize, c: char) -> usize {
+        match *self {
+            InternalStream::Mmap { ref mmap } => mmap.byte_len(byte_offset, c),
+            _ => c.len_utf8(),
+        }
+    }
+
+    ///
+    /// count of bytes in the input of `c`, which ends at the byte offset.
+    ///
+    pub fn byte_len_back(&self, byte_offset: usize, c: char) -> usize {
+        match *self {
+            InternalStream::Mmap { ref mmap } => mmap.byte_len_back(byte_offset, c),
+            _ => c.len_utf8(),
+        }
+    }
+
+    ///
+    /// position of the byte offset of the input, for the stream which can `seek`.
+    ///
+    pub fn position_at(&self, byte_offset: usize) -> Option<Position> {
+        match *self {
+            InternalStream::Mmap { ref mmap } => Some(mmap.position_at(byte_offset)),
+            _ => None,
+        }
+    }
+}
@@ -4,29 +4,184 @@
 // of the MIT license.  See the LICENSE file for details.
 //! # char_stream
 //!
-//! char_stream gives a unified character reading interface to str, String, bytes, File and Stdin.
+//! char_stream gives a unified character reading interface to str, String, bytes, File, Stdin and any reader.
 //!
+extern crate encoding_rs;
+extern crate memmap2;
+
+mod encoding;
+mod error;
+mod follow;
 mod internals;
+mod line_endings;
+mod lines;
+mod lines_rev;
+mod mark;
+mod origin;
+mod position;
+mod source;
 mod wend_iter;
 
+pub use encoding::{DecodeMode, Detection, Encoding};
+pub use error::Error;
+pub use follow::FollowHandle;
+pub use line_endings::LineEndings;
+pub use lines::Lines;
+pub use lines_rev::LinesRev;
+pub use mark::Mark;
+pub use origin::Origin;
+pub use position::Position;
+pub use source::CharSource;
+pub use wend_iter::WendIterator;
+
+use std::cell::Cell;
+use std::mem;
 use std::str;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
 use std::iter::Iterator;
-use internals::{InternalCharVec, InternalFile, InternalStdin};
-use wend_iter::WendIterator;
+use internals::{InternalChain, InternalCharVec, InternalMmap, InternalStr, InternalFile, InternalFollow, InternalInclude, InternalReader, InternalSplice, InternalStdin, InternalStream};
+use encoding::{Decoder, DETECT_SIZE};
+use line_endings::LineBreak;
+use mark::Retention;
+
+// count of line ends remembered to restore the column on `unread('\n')`.
+const LINE_END_HISTORY: usize = 64;
+
+// a line break, remembered to restore the position on `unread`.
+#[derive(Debug, Clone, Copy)]
+struct LineEnd {
+    char_offset: usize,
+    column: usize,
+    line_break: LineBreak,
+    after_cr: bool,
+}
+
+// where a char read ahead from `chain`, `with_includes` or `splice` came from.
+#[derive(Debug, Clone)]
+struct Lookahead {
+    index: Option<usize>,
+    path: Option<PathBuf>,
+    position: Position,
+    include_chain: Vec<(Option<PathBuf>, Position)>,
+}
+
+// byte offset which `seek` moved to. its position is counted when it is needed,
+// and the position of the stream is counted from there until then.
+#[derive(Debug)]
+struct SeekBase {
+    byte_offset: usize,
+    position: Cell<Option<Position>>,
+}
+
+// default limit of nested `CharStream::splice`.
+const DEFAULT_SPLICE_LIMIT: usize = 64;
 
 #[derive(Debug)]
-pub enum CharStream {
-    Chars { chars: InternalCharVec },
-    File { file: InternalFile },
-    StdIn { stdin: InternalStdin },
+pub struct CharStream<'a> {
+    inner: InternalStream<'a>,
+    buffer: VecDeque<char>,
+    lookahead: VecDeque<Lookahead>,
+    position: Position,
+    line_ends: VecDeque<LineEnd>,
+    line_endings: LineEndings,
+    after_cr: bool,
+    seek_base: Option<SeekBase>,
+    retention: Retention,
+    replacements: usize,
+    path: Option<PathBuf>,
+    splice_limit: usize,
 }
 
-impl CharStream {
+impl<'a> CharStream<'a> {
+    fn new(inner: InternalStream<'a>) -> CharStream<'a> {
+        let seekable = inner.is_seekable();
+        CharStream {
+            inner,
+            buffer: VecDeque::new(),
+            lookahead: VecDeque::new(),
+            position: Position::new(),
+            line_ends: VecDeque::new(),
+            line_endings: LineEndings::default(),
+            after_cr: false,
+            seek_base: None,
+            retention: Retention::new(seekable),
+            replacements: 0,
+            path: None,
+            splice_limit: DEFAULT_SPLICE_LIMIT,
+        }
+    }
+
+    // `open` で開いたストリームのエラーには、ファイル名を付ける。
+    fn in_file(&self, error: Error) -> Error {
+        match self.path {
+            Some(ref path) => Error::InFile { path: path.clone(), error: Box::new(error) },
+            None => error,
+        }
+    }
+
+    fn advance(&mut self, c: char) {
+        let line_break = self.line_endings.line_break(c, self.after_cr);
+        if line_break != LineBreak::No {
+            if self.line_ends.len() == LINE_END_HISTORY {
+                self.line_ends.pop_front();
+            }
+            self.line_ends.push_back(LineEnd {
+                char_offset: self.position.char_offset,
+                column: self.position.column,
+                line_break,
+                after_cr: self.after_cr,
+            });
+        }
+        self.after_cr = c == '\r';
+        self.retention.push(c, self.position.char_offset);
+        // 不正なバイトを置き換えたり読み飛ばしたりしたファイルでは、ファイルの中のバイト数を数える。
+        let len = self.inner.byte_len(self.seek_offset() + self.position.byte_offset, c);
+        self.position.advance(c, line_break);
+        self.position.byte_offset = self.position.byte_offset - c.len_utf8() + len;
+    }
+
+    // seek した位置のバイトオフセット。position はここからの相対位置になる。
+    fn seek_offset(&self) -> usize {
+        self.seek_base.as_ref().map_or(0, |base| base.byte_offset)
+    }
+
+    fn retreat(&mut self, c: char) {
+        if self.position.char_offset == 0 {
+            self.resolve_position();
+        }
+        let len = self.inner.byte_len_back(self.seek_offset() + self.position.byte_offset, c);
+        let position = &mut self.position;
+        position.char_offset = position.char_offset.saturating_sub(1);
+        position.byte_offset = position.byte_offset.saturating_sub(len);
+        self.retention.truncate(position.char_offset);
+        self.after_cr = false;
+
+        match self.line_ends.back().cloned() {
+            Some(end) if end.char_offset == position.char_offset => {
+                self.line_ends.pop_back();
+                if end.line_break == LineBreak::Yes && position.line > 1 {
+                    position.line -= 1;
+                }
+                position.column = end.column;
+                self.after_cr = end.after_cr;
+            },
+            _ => {
+                if position.column > 1 {
+                    position.column -= 1;
+                }else if position.line > 1 && self.line_endings.line_break(c, true) != LineBreak::No {
+                    // 覚えていない改行は、列がわからない。
+                    position.line -= 1;
+                }
+            },
+        }
+    }
 
     ///
     /// make new CharStream from str.
+    ///  the str is borrowed without copying, so `slice_since` can return slices of it.
     ///
     /// Example:
     ///
@@ -47,10 +202,10 @@ impl CharStream {
     /// assert_eq!(None, stream.next());
     /// ```
     ///
-    pub fn from(s: &str) -> CharStream {
-        CharStream::Chars {
-            chars: InternalCharVec::new(s.chars().collect())
-        }
+    pub fn from(s: &'a str) -> CharStream<'a> {
+        CharStream::new(InternalStream::Str {
+            chars: InternalStr::new(s)
+        })
     }
 
     ///
@@ -76,14 +231,15 @@ impl CharStream {
     /// assert_eq!(None, stream.next());
     /// ```
     ///
-    pub fn from_string(s: String) -> CharStream {
-        CharStream::Chars {
+    pub fn from_string(s: String) -> CharStream<'a> {
+        CharStream::new(InternalStream::Chars {
             chars: InternalCharVec::new(s.chars().collect()),
-        }
+        })
     }
 
     ///
     /// make new CharStream from bytes.
+    ///  returns `Error::InvalidUtf8` if bytes are not valid UTF-8.
     ///
     /// Example:
     ///
@@ -105,16 +261,86 @@ impl CharStream {
     /// }
     /// ```
     ///
-    pub fn from_bytes(bytes: &[u8]) -> Result<CharStream, &str> {
-        if let Ok(s) = str::from_utf8(bytes) {
-            Ok(CharStream::Chars {
+    pub fn from_bytes(bytes: &[u8]) -> Result<CharStream<'a>, Error> {
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(CharStream::new(InternalStream::Chars {
                 chars: InternalCharVec::new(s.chars().collect())
-            })
-        }else{
-            Err("can't convert utf8 string from bytes.")
+            })),
+            Err(err) => Err(encoding::invalid_utf8(bytes, &err)),
         }
     }
 
+    ///
+    /// make new CharStream from bytes in the encoding.
+    ///  a byte order mark at the start of bytes is skipped and its encoding is used.
+    ///  returns the error if bytes are malformed.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Encoding};
+    ///
+    /// let bytes = [0x16, 0x4e, 0x4c, 0x75, 0x64, 0x27, 0x3d, 0xd8, 0x00, 0xde];
+    /// let mut stream = CharStream::from_bytes_with_encoding(&bytes, Encoding::Utf16Le).unwrap();
+    /// assert_eq!("世界❤😀", stream.to_string());
+    ///
+    /// let bytes = [0xfe, 0xff, 0x4e, 0x16, 0x75, 0x4c];
+    /// let mut stream = CharStream::from_bytes_with_encoding(&bytes, Encoding::Utf8).unwrap();
+    /// assert_eq!("世界", stream.to_string());
+    /// ```
+    ///
+    pub fn from_bytes_with_encoding(bytes: &[u8], encoding: Encoding) -> Result<CharStream<'a>, Error> {
+        CharStream::from_bytes_with_mode(bytes, encoding, DecodeMode::Strict)
+    }
+
+    ///
+    /// make new CharStream from bytes in the encoding.
+    ///  malformed sequences are handled by `mode`. returns the error only in `DecodeMode::Strict`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, DecodeMode, Encoding};
+    ///
+    /// let bytes = [0x61, 0xf1, 0x80, 0x80, 0xe1, 0x80, 0xc2, 0x62, 0x80, 0x63];
+    /// let mut stream = CharStream::from_bytes_with_mode(&bytes, Encoding::Utf8, DecodeMode::Lossy).unwrap();
+    /// assert_eq!("a\u{fffd}\u{fffd}\u{fffd}b\u{fffd}c", stream.to_string());
+    /// assert_eq!(4, stream.replacements());
+    ///
+    /// let mut stream = CharStream::from_bytes_with_mode(&bytes, Encoding::Utf8, DecodeMode::Skip).unwrap();
+    /// assert_eq!("abc", stream.to_string());
+    /// ```
+    ///
+    pub fn from_bytes_with_mode(bytes: &[u8], encoding: Encoding, mode: DecodeMode) -> Result<CharStream<'a>, Error> {
+        let (chars, replacements) = encoding::decode_all(bytes, encoding, mode)?;
+        let mut stream = CharStream::new(InternalStream::Chars {
+            chars: InternalCharVec::new(chars)
+        });
+        stream.replacements = replacements;
+        Ok(stream)
+    }
+
+    ///
+    /// make new CharStream from bytes in an unknown encoding.
+    /// returns the stream and the detected encoding. See `Encoding::detect`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Encoding};
+    ///
+    /// let bytes = [0xa4, 0xb3, 0xa4, 0xf3, 0xa4, 0xcb, 0xa4, 0xc1, 0xa4, 0xcf];
+    /// let (mut stream, detection) = CharStream::from_bytes_detect(&bytes).unwrap();
+    /// assert_eq!(Encoding::EucJp, detection.encoding);
+    /// assert_eq!("こんにちは", stream.to_string());
+    /// ```
+    ///
+    pub fn from_bytes_detect(bytes: &[u8]) -> Result<(CharStream<'a>, Detection), Error> {
+        let detection = Encoding::detect(bytes);
+        let stream = CharStream::from_bytes_with_encoding(bytes, detection.encoding)?;
+        Ok((stream, detection))
+    }
+
     ///
     /// make new CharStream from File.
     ///
@@ -156,183 +382,1550 @@ impl CharStream {
     /// # }
     /// ```
     ///
-     pub fn from_file(file: File) -> CharStream {
-        CharStream::File {
-            file: InternalFile::new(file)
-        }
+    pub fn from_file(file: File) -> CharStream<'a> {
+        CharStream::new(InternalStream::File {
+            file: InternalFile::from_file(file)
+        })
     }
 
     ///
-    /// make new CharStream from stdin.
+    /// make new CharStream from File in the encoding.
+    ///  a byte order mark at the start of the file is skipped and its encoding is used.
     ///
     /// Example:
     ///
-    /// ```no_run
+    /// ```
+    /// extern crate tempfile;
     /// extern crate char_stream;
     ///
-    /// use char_stream::CharStream;
+    /// use std::io::prelude::*;
+    /// use std::io::{Seek, SeekFrom};
+    /// use std::fs::File;
+    /// use char_stream::{CharStream, Encoding};
     ///
-    /// fn main() {
-    ///     let mut stream = CharStream::from_stdin();
-    ///     while let Some(ch) = stream.next() {
-    ///         println!("ch: {}", ch);
-    ///     }
-    /// }
+    /// # fn main(){
+    /// let mut tmpfile: File = tempfile::tempfile().unwrap();
+    /// tmpfile.write_all(&[0xff, 0xfe, 0x48, 0x00, 0x69, 0x00, 0x0a, 0x00, 0x16, 0x4e, 0x4c, 0x75]).unwrap();
+    /// tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    ///
+    /// let mut stream = CharStream::from_file_with_encoding(tmpfile, Encoding::Utf16Le);
+    /// assert_eq!("Hi", stream.read_line().unwrap());
+    /// assert_eq!("世界", stream.read_line().unwrap());
+    /// assert_eq!(None, stream.next());
+    /// # }
     /// ```
     ///
-    pub fn from_stdin() -> CharStream {
-        let internal = InternalStdin::new(io::stdin());
-        CharStream::StdIn {
-            stdin: internal
-        }
+    pub fn from_file_with_encoding(file: File, encoding: Encoding) -> CharStream<'a> {
+        CharStream::new(InternalStream::File {
+            file: InternalFile::from_file_with_decoder(file, Decoder::with_bom_sniffing(encoding))
+        })
     }
 
     ///
-    /// peek a next char
+    /// make new CharStream from File in an unknown encoding.
+    /// The encoding is detected from the first 64 KiB, and then the file is read from the current position.
     ///
-    pub fn peek(&mut self) -> Option<char> {
-        match self {
-            &mut CharStream::Chars { ref mut chars } => {
-                chars.peek()
-            },
-            &mut CharStream::File { ref mut file } => {
-                file.peek()
-            },
-            &mut CharStream::StdIn { ref mut stdin } => {
-                stdin.peek()
-            },
-        }
+    /// Example:
+    ///
+    /// ```
+    /// extern crate tempfile;
+    /// extern crate char_stream;
+    ///
+    /// use std::io::prelude::*;
+    /// use std::io::{Seek, SeekFrom};
+    /// use std::fs::File;
+    /// use char_stream::{CharStream, Encoding};
+    ///
+    /// # fn main(){
+    /// let mut tmpfile: File = tempfile::tempfile().unwrap();
+    /// tmpfile.write_all(&[0x90, 0xa2, 0x8a, 0x45, 0x0a, 0x82, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd]).unwrap();
+    /// tmpfile.seek(SeekFrom::Start(0)).unwrap();
+    ///
+    /// let (mut stream, detection) = CharStream::from_file_detect(tmpfile).unwrap();
+    /// assert_eq!(Encoding::ShiftJis, detection.encoding);
+    /// assert_eq!("世界", stream.read_line().unwrap());
+    /// assert_eq!("こんにちは", stream.read_line().unwrap());
+    /// # }
+    /// ```
+    ///
+    pub fn from_file_detect(mut file: File) -> Result<(CharStream<'a>, Detection), Error> {
+        let start = file.stream_position()?;
+        let mut prefix = Vec::with_capacity(DETECT_SIZE);
+        (&mut file).take(DETECT_SIZE as u64).read_to_end(&mut prefix)?;
+        file.seek(SeekFrom::Start(start))?;
+
+        let detection = Encoding::detect(&prefix);
+        Ok((CharStream::from_file_with_encoding(file, detection.encoding), detection))
     }
 
     ///
-    /// read a line
+    /// open the file at the path and make new CharStream from it.
+    ///  `-` is read from stdin, like Unix tools.
+    ///  the path is kept by the stream, and errors are returned as `Error::InFile` with the path.
     ///
     /// Example:
     ///
     /// ```
+    /// extern crate tempfile;
     /// extern crate char_stream;
     ///
-    /// use char_stream::CharStream;
+    /// use std::io::prelude::*;
+    /// use char_stream::{CharStream, Error};
     ///
-    /// fn main(){
-    ///     let test_data = "Hello\n 世界❤";
-    ///     let mut stream = CharStream::from(test_data);
-    ///     assert_eq!("Hello", stream.read_line().unwrap());
-    ///     assert_eq!(" 世界❤", stream.read_line().unwrap());
-    ///     assert_eq!(None, stream.next());
+    /// # fn main(){
+    /// let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+    /// tmpfile.write_all("Hello\n 世界❤".as_bytes()).unwrap();
+    ///
+    /// let mut stream = CharStream::open(tmpfile.path()).unwrap();
+    /// assert_eq!(Some(tmpfile.path()), stream.path());
+    /// assert_eq!("Hello", stream.read_line().unwrap());
+    /// assert_eq!(format!("{}:2:1", tmpfile.path().display()), stream.location());
+    ///
+    /// match CharStream::open("no/such/file") {
+    ///     Err(Error::InFile { path, .. }) => assert_eq!("no/such/file", path.to_str().unwrap()),
+    ///     _ => panic!("the file doesn't exist"),
     /// }
+    /// # }
     /// ```
     ///
-    pub fn read_line(&mut self) -> Option<String> {
-        if let None = self.peek() {
-            return None;
-        }
-
-        let mut result = String::new();
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CharStream<'a>, Error> {
+        let path = path.as_ref();
+        let inner = if path == Path::new("-") {
+            InternalStream::StdIn {
+                stdin: InternalStdin::new(io::stdin())
+            }
+        }else{
+            match InternalFile::open(path) {
+                Ok(file) => InternalStream::File { file },
+                Err(error) => return Err(Error::InFile { path: path.to_path_buf(), error: Box::new(error) }),
+            }
+        };
 
-        while let Some(c) = self.next() {
-            if c == '\n' {
-                break;
+        let mut stream = CharStream::new(inner);
+        stream.path = Some(path.to_path_buf());
+        Ok(stream)
+    }
 
-            } else if c == '\r' {
-                if let Some(c2) = self.peek() {
-                    if c2 == '\n' {
-                        self.next();
-                        break;
-                    }
-                }else{
-                    break;
-                }
-            }
+    ///
+    /// path of the file opened by `open`.
+    ///  after `splice`, it is the path of the original input.
+    ///  returns None for other streams.
+    ///
+    pub fn path(&self) -> Option<&Path> {
+        match self.inner {
+            InternalStream::Splice { ref splice } => splice.original_path(),
+            _ => self.path.as_deref(),
+        }
+    }
 
-            result.push(c);
+    ///
+    /// position of the next char as `path:line:column`, or `line:column` if the stream has no path.
+    ///  for CharStream made by `chain`, `from_paths` or `with_includes`, it is the path and the position in the current source.
+    ///  after `splice`, it is the path and the position in the original input.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("a\nb");
+    /// stream.read_line();
+    /// assert_eq!("2:1", stream.location());
+    /// ```
+    ///
+    pub fn location(&self) -> String {
+        let (path, position) = match self.source_position() {
+            Some(position) => (self.source_path(), position),
+            None => (self.path(), self.position()),
+        };
+        match path {
+            Some(path) => format!("{}:{}", path.display(), position),
+            None => position.to_string(),
         }
+    }
 
-        Some(result)
+    ///
+    /// make new CharStream which reads the streams in sequence as one stream.
+    ///  `source_index`, `source_path` and `source_position` tell which stream the next char comes from.
+    ///  after an error is returned by `try_next` or `try_peek`, the rest of the stream which caused it is skipped.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let sources = vec![CharStream::from("a = 1"), CharStream::from("b = 2\n"), CharStream::from("c = 3")];
+    /// let mut stream = CharStream::chain(sources);
+    /// stream.set_insert_newline(true);
+    ///
+    /// assert_eq!("a = 1", stream.read_line().unwrap());
+    /// assert_eq!(Some(1), stream.source_index());
+    /// assert_eq!("b = 2", stream.read_line().unwrap());
+    /// stream.nth(3);
+    /// assert_eq!(Some(2), stream.source_index());
+    /// assert_eq!("1:5", stream.location());
+    /// assert_eq!(3, stream.position().line);
+    /// assert_eq!("3", stream.to_string());
+    /// ```
+    ///
+    pub fn chain(streams: Vec<CharStream<'a>>) -> CharStream<'a> {
+        CharStream::new(InternalStream::Chain {
+            chain: InternalChain::new(streams)
+        })
     }
 
     ///
-    /// to string
+    /// open the files at the paths by `open`, and make new CharStream which reads them in sequence as `chain`.
+    ///  returns the error of the first file which can't be opened.
     ///
     /// Example:
-    /// 
+    ///
     /// ```
+    /// extern crate tempfile;
+    /// extern crate char_stream;
+    ///
+    /// use std::io::prelude::*;
     /// use char_stream::CharStream;
-    /// 
-    /// let s = String::from("Hello 世界❤");
-    /// let mut stream = CharStream::from_string(s);
-    /// let result = stream.to_string();
     ///
-    /// assert_eq!("Hello 世界❤", result);
+    /// # fn main(){
+    /// let mut config = tempfile::NamedTempFile::new().unwrap();
+    /// config.write_all(b"include = extra\n").unwrap();
+    /// let mut extra = tempfile::NamedTempFile::new().unwrap();
+    /// extra.write_all(b"x = 1\n").unwrap();
+    ///
+    /// let mut stream = CharStream::from_paths(&[config.path(), extra.path()]).unwrap();
+    /// stream.read_line();
+    /// assert_eq!(Some('x'), stream.peek());
+    /// assert_eq!(Some(extra.path()), stream.source_path());
+    /// assert_eq!(format!("{}:1:1", extra.path().display()), stream.location());
+    /// # }
     /// ```
     ///
-    pub fn to_string(&mut self) -> String {
-        let mut string = String::new();
+    pub fn from_paths<P: AsRef<Path>>(paths: &[P]) -> Result<CharStream<'a>, Error> {
+        let mut streams = Vec::with_capacity(paths.len());
+        for path in paths {
+            streams.push(CharStream::open(path)?);
+        }
+        Ok(CharStream::chain(streams))
+    }
 
-        while let Some(c) = self.next() {
-            string.push(c);
+    ///
+    /// insert '\n' between the streams which are read by `chain` or `from_paths`, if the former stream doesn't end with it.
+    ///  this is ignored by other streams.
+    ///
+    pub fn set_insert_newline(&mut self, insert: bool) {
+        if let InternalStream::Chain { ref mut chain } = self.inner {
+            chain.set_insert_newline(insert);
         }
+    }
 
-        string
+    ///
+    /// index of the stream which the next char comes from, for CharStream made by `chain` or `from_paths`.
+    ///  at the end of a stream, it is updated when the next char is peeked.
+    ///  returns None for other streams.
+    ///
+    pub fn source_index(&self) -> Option<usize> {
+        match self.lookahead.front() {
+            Some(lookahead) => lookahead.index,
+            None => self.inner.source_index(),
+        }
     }
 
     ///
-    /// Convert to DoubleEndedIterator.
-    ///  caution: CharStream made by 'from_stdin' can't convert.
+    /// path of the stream which the next char comes from, for CharStream made by `chain`, `from_paths` or `with_includes`.
+    ///  after `splice`, it is the path of the original input.
+    ///  returns None for other streams, or if the stream was not made by `open`.
+    ///
+    pub fn source_path(&self) -> Option<&Path> {
+        match self.lookahead.front() {
+            Some(lookahead) => lookahead.path.as_deref(),
+            None => self.inner.source_path(),
+        }
+    }
+
+    ///
+    /// position of the next char in the stream which it comes from, for CharStream made by `chain`, `from_paths` or `with_includes`.
+    ///  after `splice`, it is the position in the original input, which doesn't count chars of expansions.
+    ///  chars pushed back by `unread` are not counted, and chars read ahead by `peek_nth` or `starts_with` are not yet.
+    ///  returns None for other streams.
+    ///
+    pub fn source_position(&self) -> Option<Position> {
+        match self.lookahead.front() {
+            Some(lookahead) => Some(lookahead.position),
+            None => self.inner.source_position(),
+        }
+    }
+
+    ///
+    /// make new CharStream which can read files included by `include` in the middle of the stream.
     ///
     /// Example:
     ///
     /// ```
+    /// extern crate tempfile;
     /// extern crate char_stream;
     ///
+    /// use std::io::prelude::*;
     /// use char_stream::CharStream;
     ///
-    /// fn main() {
-    ///     let input = "stressed";
-    ///     let stream = CharStream::from(input);
-    ///     let mut rev_stream = stream.wend_iter().rev();
-    ///     let mut result = String::new();
-    ///     while let Some(c) = rev_stream.next() {
-    ///         result.push(c);
+    /// # fn main(){
+    /// let mut sub = tempfile::NamedTempFile::new().unwrap();
+    /// sub.write_all(b"b\n").unwrap();
+    /// let mut main = tempfile::NamedTempFile::new().unwrap();
+    /// write!(main, "a\n#include \"{}\"\nc\n", sub.path().display()).unwrap();
+    ///
+    /// let mut stream = CharStream::with_includes(CharStream::open(main.path()).unwrap());
+    /// let mut lines = Vec::new();
+    /// while let Some(line) = stream.read_line() {
+    ///     match line.strip_prefix("#include ") {
+    ///         Some(path) => stream.include(path.trim_matches('"')).unwrap(),
+    ///         None => lines.push(line),
     ///     }
-    ///     println!("'{}' reverse to '{}'", input , result);
     /// }
+    /// assert_eq!(vec!["a", "b", "c"], lines);
+    /// # }
     /// ```
     ///
-    pub fn wend_iter(self) -> WendIterator {
-        match self {
-            CharStream::Chars { chars } => WendIterator::from_chars(chars),
-            CharStream::File { file } => {
-                let chars = file.read_and_get_all_chars();
-                let char_vec = InternalCharVec::new(chars);
-                WendIterator::from_chars(char_vec)
+    pub fn with_includes(stream: CharStream<'a>) -> CharStream<'a> {
+        CharStream::new(InternalStream::Include {
+            include: InternalInclude::new(stream)
+        })
+    }
+
+    ///
+    /// read the file at the path before the rest of the current stream, for CharStream made by `with_includes`.
+    ///  a relative path is resolved from the directory of the file being read.
+    ///  the rest of the current stream, including chars pushed back by `unread`, is read at the end of the file.
+    ///  returns `Error::IncludeCycle` if the file is already being read,
+    ///  and `Error::UnsupportedOperation` for other streams.
+    ///
+    pub fn include<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        match self.inner {
+            InternalStream::Include { ref mut include } => {
+                let result = include.include(path.as_ref(), &mut self.buffer);
+                self.trim_lookahead();
+                result
             },
-            CharStream::StdIn { .. } => panic!("can't convert DoubleEndedIterator from CharStream made by 'from_stdin'"),
+            _ => Err(Error::UnsupportedOperation("can't include a file into CharStream not made by 'with_includes'")),
         }
     }
-}
 
-impl Iterator for CharStream {
-    type Item = char;
+    ///
+    /// read the stream before the rest of this stream, like a macro expansion.
+    ///  the stream can be spliced while reading a spliced one, up to the limit set by `set_splice_limit`.
+    ///  chars pushed back by `unread` are read after the spliced stream.
+    ///  `reset` to a mark made before the splice reads the same chars again, including the spliced ones.
+    ///  returns `Error::SpliceLimit` if the splices are nested too deeply.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Origin};
+    ///
+    /// let mut stream = CharStream::from("x = PI * r");
+    /// let mut result = String::new();
+    /// while let Some(c) = stream.next() {
+    ///     if c == 'P' && stream.peek() == Some('I') {
+    ///         stream.next();
+    ///         stream.splice_str("3.14").unwrap();
+    ///         continue;
+    ///     }
+    ///     if stream.origin() != Origin::Original {
+    ///         result.push('_');
+    ///     }
+    ///     result.push(c);
+    /// }
+    /// assert_eq!("x = _3_._1_4 * r", result);
+    /// assert_eq!(10, stream.source_position().unwrap().char_offset);
+    /// ```
+    ///
+    pub fn splice(&mut self, mut source: CharStream<'a>) -> Result<(), Error> {
+        source.set_line_endings(self.line_endings);
+        if let InternalStream::Splice { ref mut splice } = self.inner {
+            let result = splice.splice(source, &mut self.buffer);
+            self.trim_lookahead();
+            return result;
+        }
 
-    fn next(&mut self) -> Option<char> {
-        match self {
-            &mut CharStream::Chars { ref mut chars } => {
-                chars.next()
-            },
-            &mut CharStream::File { ref mut file } => {
-                file.next()
-            },
-            &mut CharStream::StdIn { ref mut stdin } => {
-                stdin.next()
+        // 展開を挟むとマークへ直接戻れないので、マークから読んだ文字を覚えておく。
+        self.keep_marked_chars();
+
+        // 元のストリームを、展開を積むスタックの底にする。
+        self.resolve_position();
+        let inner = mem::replace(&mut self.inner, InternalStream::Chars {
+            chars: InternalCharVec::new(Vec::new())
+        });
+        let mut original = CharStream::new(inner);
+        original.buffer = mem::take(&mut self.buffer);
+        original.lookahead = mem::take(&mut self.lookahead);
+        original.position = self.position;
+        original.line_ends = self.line_ends.clone();
+        original.line_endings = self.line_endings;
+        original.after_cr = self.after_cr;
+        original.replacements = self.replacements;
+        // パスは元のストリームに移して、エラーにファイル名を二重に付けない。
+        original.path = self.path.take();
+        self.replacements = 0;
+        self.retention.set_seekable(false);
+
+        let mut splice = InternalSplice::new(original, self.splice_limit);
+        let result = splice.splice(source, &mut self.buffer);
+        self.inner = InternalStream::Splice { splice };
+        result
+    }
+
+    // マークから読んだ文字を読み直して、覚えておく。
+    fn keep_marked_chars(&mut self) {
+        if self.retention.keeps_chars() {
+            return;
+        }
+        self.resolve_position();
+        let end = self.position.char_offset;
+        match self.retention.oldest() {
+            Some(ref mark) if mark.position().char_offset < end => {
+                self.reset(mark);
+                self.retention.keep_chars(mark.position().char_offset);
+                while self.position.char_offset < end && self.next().is_some() {}
             },
+            _ => self.retention.keep_chars(end),
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    extern crate tempfile;
+
+    ///
+    /// read a copy of the str before the rest of this stream, as `splice`.
+    ///
+    pub fn splice_str(&mut self, s: &str) -> Result<(), Error> {
+        self.splice(CharStream::from_string(s.to_string()))
+    }
+
+    ///
+    /// set the limit of nested `splice`. the default is 64.
+    ///
+    pub fn set_splice_limit(&mut self, limit: usize) {
+        self.splice_limit = limit;
+        if let InternalStream::Splice { ref mut splice } = self.inner {
+            splice.set_limit(limit);
+        }
+    }
+
+    ///
+    /// whether the char read last came from the original input or from a stream spliced by `splice`.
+    ///  a spliced stream is nested into the expansion which the char read last came from,
+    ///  so a recursive expansion is caught by the limit even at the end of the expansion.
+    ///  chars pushed back by `unread` are counted as chars of the stream which is read after them.
+    ///
+    pub fn origin(&self) -> Origin {
+        match self.inner {
+            InternalStream::Splice { ref splice } => splice.origin(),
+            _ => Origin::Original,
+        }
+    }
+
+    ///
+    /// paths and positions of the files being read, from the outermost one, for CharStream made by `with_includes`.
+    ///  the last one is the file which the next char comes from.
+    ///  returns an empty Vec for other streams.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Position};
+    ///
+    /// let stream = CharStream::with_includes(CharStream::from("a"));
+    /// assert_eq!(vec![(None, Position::new())], stream.include_chain());
+    /// ```
+    ///
+    pub fn include_chain(&self) -> Vec<(Option<&Path>, Position)> {
+        match self.inner {
+            InternalStream::Include { ref include } => {
+                // 先読みした文字は、まだ読んでいない。
+                match self.lookahead.front() {
+                    Some(lookahead) => lookahead.include_chain.iter().map(|&(ref path, position)| (path.as_deref(), position)).collect(),
+                    None => include.chain(),
+                }
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    ///
+    /// make new CharStream from a memory-mapped UTF-8 file.
+    ///  chars are read directly from the mapped bytes, so `reset`, `seek` and `wend_iter` are cheap.
+    ///  a file which is not a regular file, like a pipe, is read by buffers as `from_file`.
+    ///  the path is kept as `open` does, so `location` and errors include it.
+    ///  returns an error if the file can't be opened. invalid UTF-8 is handled by `set_decode_mode` when it is read.
+    ///  `byte_offset` of positions is the offset in the file, which includes invalid bytes.
+    ///  caution: the file must not be modified or truncated, by this or any other process, while the stream is alive.
+    ///  the mapped bytes would change under the stream, and reading a truncated part raises SIGBUS,
+    ///  which kills the process. use `open` for files which can be written while they are read.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// extern crate tempfile;
+    /// extern crate char_stream;
+    ///
+    /// use std::io::prelude::*;
+    /// use char_stream::CharStream;
+    ///
+    /// # fn main(){
+    /// let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+    /// tmpfile.write_all("Hello\n 世界❤".as_bytes()).unwrap();
+    ///
+    /// let mut stream = CharStream::from_path_mmap(tmpfile.path()).unwrap();
+    /// assert_eq!("Hello", stream.read_line().unwrap());
+    ///
+    /// stream.seek(7).unwrap();
+    /// assert_eq!(Some('世'), stream.next());
+    /// assert_eq!(2, stream.position().line);
+    /// assert_eq!(3, stream.position().column);
+    /// # }
+    /// ```
+    ///
+    pub fn from_path_mmap<P: AsRef<Path>>(path: P) -> Result<CharStream<'a>, Error> {
+        let path = path.as_ref();
+        let in_file = |error: Error| Error::InFile { path: path.to_path_buf(), error: Box::new(error) };
+        let file = File::open(path).map_err(|err| in_file(Error::Io(err)))?;
+        let metadata = file.metadata().map_err(|err| in_file(Error::Io(err)))?;
+        let inner = if metadata.is_file() {
+            InternalStream::Mmap { mmap: InternalMmap::map(&file).map_err(in_file)? }
+        }else{
+            InternalStream::File { file: InternalFile::from_file(file) }
+        };
+
+        let mut stream = CharStream::new(inner);
+        stream.path = Some(path.to_path_buf());
+        Ok(stream)
+    }
+
+    ///
+    /// make new CharStream from any reader.
+    ///  the reader can borrow data which lives as long as the stream, and must be `Send`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::CharStream;
+    ///
+    /// let cursor = Cursor::new("Hello\n 世界❤".as_bytes().to_vec());
+    /// let mut stream = CharStream::from_reader(cursor);
+    ///
+    /// assert_eq!("Hello", stream.read_line().unwrap());
+    /// assert_eq!(' ', stream.next().unwrap());
+    /// assert_eq!('世', stream.next().unwrap());
+    /// assert_eq!('界', stream.next().unwrap());
+    /// assert_eq!('❤', stream.next().unwrap());
+    /// assert_eq!(None, stream.next());
+    /// ```
+    ///
+    pub fn from_reader<R: Read + Send + 'a>(reader: R) -> CharStream<'a> {
+        CharStream::from_buf_reader(BufReader::new(reader))
+    }
+
+    ///
+    /// make new CharStream from a reader which is already buffered.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::CharStream;
+    ///
+    /// let cursor = Cursor::new("Hello 世界❤");
+    /// let mut stream = CharStream::from_buf_reader(cursor);
+    ///
+    /// assert_eq!("Hello 世界❤", stream.to_string());
+    /// ```
+    ///
+    pub fn from_buf_reader<R: BufRead + Send + 'a>(reader: R) -> CharStream<'a> {
+        let reader: Box<dyn BufRead + Send + 'a> = Box::new(reader);
+        CharStream::new(InternalStream::Reader {
+            reader: InternalReader::new(reader)
+        })
+    }
+
+    ///
+    /// make new CharStream from any reader in the encoding.
+    ///  a byte order mark at the start of the reader is skipped and its encoding is used.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::{CharStream, Encoding};
+    ///
+    /// let cursor = Cursor::new(vec![0x00, 0x00, 0x4e, 0x16, 0x00, 0x00, 0x75, 0x4c]);
+    /// let mut stream = CharStream::from_reader_with_encoding(cursor, Encoding::Utf32Be);
+    ///
+    /// assert_eq!("世界", stream.to_string());
+    /// ```
+    ///
+    pub fn from_reader_with_encoding<R: Read + Send + 'a>(reader: R, encoding: Encoding) -> CharStream<'a> {
+        let reader: Box<dyn BufRead + Send + 'a> = Box::new(BufReader::new(reader));
+        CharStream::new(InternalStream::Reader {
+            reader: InternalReader::with_decoder(reader, Decoder::with_bom_sniffing(encoding))
+        })
+    }
+
+    ///
+    /// make new CharStream from user defined CharSource.
+    ///  the source must be `Send`, so the stream can be sent to another thread.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharSource, CharStream};
+    ///
+    /// struct Digits {
+    ///     position: usize,
+    /// }
+    ///
+    /// impl CharSource for Digits {
+    ///     fn next(&mut self) -> Option<char> {
+    ///         let result = self.peek();
+    ///         self.position += 1;
+    ///         result
+    ///     }
+    ///
+    ///     fn peek(&mut self) -> Option<char> {
+    ///         std::char::from_digit(self.position as u32, 10)
+    ///     }
+    ///
+    ///     fn position(&self) -> usize {
+    ///         self.position
+    ///     }
+    /// }
+    ///
+    /// let stream = CharStream::from_source(Box::new(Digits { position: 0 }));
+    /// let result: String = stream.wend_iter().rev().collect();
+    /// assert_eq!("9876543210", result);
+    /// ```
+    ///
+    pub fn from_source(source: Box<dyn CharSource + Send + 'a>) -> CharStream<'a> {
+        CharStream::new(InternalStream::Source {
+            source
+        })
+    }
+
+    ///
+    /// make new CharStream from stdin.
+    ///
+    /// Example:
+    ///
+    /// ```no_run
+    /// extern crate char_stream;
+    ///
+    /// use char_stream::CharStream;
+    ///
+    /// fn main() {
+    ///     let mut stream = CharStream::from_stdin();
+    ///     while let Some(ch) = stream.next() {
+    ///         println!("ch: {}", ch);
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn from_stdin() -> CharStream<'a> {
+        let internal = InternalStdin::new(io::stdin());
+        CharStream::new(InternalStream::StdIn {
+            stdin: internal
+        })
+    }
+
+    ///
+    /// make new CharStream from stdin in the encoding.
+    ///  a byte order mark at the start of stdin is skipped and its encoding is used.
+    ///
+    /// Example:
+    ///
+    /// ```no_run
+    /// use char_stream::{CharStream, Encoding};
+    ///
+    /// let mut stream = CharStream::from_stdin_with_encoding(Encoding::Utf16Le);
+    /// while let Some(line) = stream.read_line() {
+    ///     println!("{}", line);
+    /// }
+    /// ```
+    ///
+    pub fn from_stdin_with_encoding(encoding: Encoding) -> CharStream<'a> {
+        let internal = InternalStdin::with_decoder(io::stdin(), Decoder::with_bom_sniffing(encoding));
+        CharStream::new(InternalStream::StdIn {
+            stdin: internal
+        })
+    }
+
+    ///
+    /// make new CharStream which follows a growing file like `tail -f`.
+    ///  the file is read from the start. at the end of the file, the stream waits for appended data.
+    ///  when the file is rotated or truncated, it is read again from the start.
+    ///  the stream ends after `FollowHandle::stop` is called.
+    ///  caution: reading at the end of the file blocks the thread,
+    ///  checking the file every `FollowHandle::poll_interval` until data is appended or the handle is stopped.
+    ///
+    /// Example:
+    ///
+    /// ```no_run
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::follow("/var/log/syslog").unwrap();
+    /// while let Some(line) = stream.read_line() {
+    ///     println!("{}", line);
+    /// }
+    /// ```
+    ///
+    pub fn follow<P: AsRef<Path>>(path: P) -> Result<CharStream<'a>, Error> {
+        let follow = InternalFollow::open(path, FollowHandle::new())?;
+        Ok(CharStream::new(InternalStream::Follow {
+            follow
+        }))
+    }
+
+    ///
+    /// handle to stop or configure CharStream made by `follow`.
+    ///  returns None for other streams.
+    ///
+    pub fn follow_handle(&self) -> Option<FollowHandle> {
+        match self.inner {
+            InternalStream::Follow { ref follow } => Some(follow.handle().clone()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// change how to handle malformed byte sequences read from now on.
+    ///  default is `DecodeMode::Strict`.
+    ///  for CharStream made by `chain` or `from_paths`, it is set to every stream in the chain.
+    ///  for CharStream made by `with_includes`, it is set to the included files too.
+    ///  after `splice`, it is set to the original input and the expansions not read yet,
+    ///  and streams spliced later keep their own mode.
+    ///  has no effect on streams which are not made from bytes, or already decoded like `from_bytes`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::{CharStream, DecodeMode};
+    ///
+    /// let mut stream = CharStream::from_reader(Cursor::new(vec![0x41, 0xff, 0x0a, 0x42, 0xc0, 0xaf]));
+    /// stream.set_decode_mode(DecodeMode::Lossy);
+    /// assert_eq!("A\u{fffd}", stream.read_line().unwrap());
+    /// assert_eq!("B\u{fffd}\u{fffd}", stream.read_line().unwrap());
+    /// assert_eq!(3, stream.replacements());
+    /// ```
+    ///
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        self.inner.set_decode_mode(mode);
+    }
+
+    ///
+    /// change count of bytes which are read and decoded at once. default is 8 KiB.
+    ///  memory used by the stream is bounded by this size regardless of the length of lines.
+    ///  has effect on streams made from File, Stdin, reader or by `follow`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from_reader(Cursor::new("世界❤".as_bytes().to_vec()));
+    /// stream.set_buffer_size(4);
+    /// assert_eq!("世界❤", stream.to_string());
+    /// ```
+    ///
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.inner.set_buffer_size(size);
+    }
+
+    ///
+    /// count of malformed byte sequences which were replaced or skipped.
+    ///
+    pub fn replacements(&self) -> usize {
+        self.replacements + self.inner.replacements()
+    }
+
+    ///
+    /// skip the malformed bytes which ended the stream, and continue reading after them.
+    ///  returns false if the stream was not ended by malformed bytes, for example at the end of input or by an I/O error.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::{CharStream, Error};
+    ///
+    /// let cursor = Cursor::new(b"ok\nb\xffd\n\xc3(\n".to_vec());
+    /// let mut stream = CharStream::from_reader(cursor);
+    ///
+    /// let mut errors = Vec::new();
+    /// loop {
+    ///     match stream.try_next() {
+    ///         Ok(Some(_)) => {},
+    ///         Ok(None) => break,
+    ///         Err(Error::InvalidUtf8 { offset, line, bytes }) => {
+    ///             errors.push((offset, line, bytes));
+    ///             assert!(stream.skip_malformed());
+    ///         },
+    ///         Err(err) => panic!("{}", err),
+    ///     }
+    /// }
+    /// assert_eq!(vec![(4, 2, vec![0xff]), (7, 3, vec![0xc3])], errors);
+    /// ```
+    ///
+    pub fn skip_malformed(&mut self) -> bool {
+        self.inner.skip_malformed()
+    }
+
+    ///
+    /// peek a next char
+    ///
+    pub fn peek(&mut self) -> Option<char> {
+        if let Some(&c) = self.buffer.front() {
+            return Some(c);
+        }
+        self.inner.peek()
+    }
+
+    ///
+    /// read a next char.
+    ///  returns `Ok(None)` at the end of stream, or the error which ended the stream.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use char_stream::{CharStream, Error};
+    ///
+    /// let cursor = Cursor::new(vec![b'H', b'i', 0xff]);
+    /// let mut stream = CharStream::from_reader(cursor);
+    ///
+    /// assert_eq!(Some('H'), stream.try_next().unwrap());
+    /// assert_eq!(Some('i'), stream.try_next().unwrap());
+    /// match stream.try_next() {
+    ///     Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(2, offset),
+    ///     _ => panic!("expected invalid utf8 error"),
+    /// }
+    /// ```
+    ///
+    pub fn try_next(&mut self) -> Result<Option<char>, Error> {
+        let result = match self.pop_buffer() {
+            Some(c) => Some(c),
+            None => match self.inner.try_next() {
+                Ok(result) => result,
+                Err(error) => return Err(self.in_file(error)),
+            },
+        };
+        if let Some(c) = result {
+            self.advance(c);
+        }
+        Ok(result)
+    }
+
+    ///
+    /// peek a next char.
+    ///  returns `Ok(None)` at the end of stream, or the error which ended the stream.
+    ///
+    pub fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        if let Some(&c) = self.buffer.front() {
+            return Ok(Some(c));
+        }
+        let result = self.inner.try_peek();
+        result.map_err(|error| self.in_file(error))
+    }
+
+    ///
+    /// peek the n-th char from the current position without consuming.
+    ///  `peek_nth(0)` is the same as `peek()`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("Hello\n 世界❤");
+    ///
+    /// assert_eq!(Some(' '), stream.peek_nth(6));
+    /// assert_eq!(Some('❤'), stream.peek_nth(9));
+    /// assert_eq!(None, stream.peek_nth(10));
+    /// assert_eq!(Some('H'), stream.next());
+    /// ```
+    ///
+    pub fn peek_nth(&mut self, n: usize) -> Option<char> {
+        while self.buffer.len() <= n {
+            let c = self.read_ahead()?;
+            self.buffer.push_back(c);
+        }
+        Some(self.buffer[n])
+    }
+
+    // 入力から先読みする。重ねたストリームでは、読んだ文字の元の位置を覚えておく。
+    fn read_ahead(&mut self) -> Option<char> {
+        if !self.inner.is_stacked() {
+            return self.inner.next();
+        }
+        self.inner.peek()?;
+        let lookahead = Lookahead {
+            index: self.inner.source_index(),
+            path: self.inner.source_path().map(|path| path.to_path_buf()),
+            position: self.inner.source_position().unwrap_or_default(),
+            include_chain: match self.inner {
+                InternalStream::Include { ref include } => {
+                    include.chain().into_iter().map(|(path, position)| (path.map(|path| path.to_path_buf()), position)).collect()
+                },
+                _ => Vec::new(),
+            },
+        };
+        let c = self.inner.next()?;
+        self.lookahead.push_back(lookahead);
+        Some(c)
+    }
+
+    // 先読みした文字は、戻した文字の後ろに並んでいる。
+    fn pop_buffer(&mut self) -> Option<char> {
+        let c = self.buffer.pop_front()?;
+        self.trim_lookahead();
+        Some(c)
+    }
+
+    fn trim_lookahead(&mut self) {
+        while self.lookahead.len() > self.buffer.len() {
+            self.lookahead.pop_front();
+        }
+    }
+
+    ///
+    /// peek next n chars without consuming.
+    ///  the result is shorter than n chars if the stream ends.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("Hello\n 世界❤");
+    ///
+    /// assert_eq!("Hello\n 世", stream.peek_n(8));
+    /// assert_eq!("Hello\n 世界❤", stream.peek_n(100));
+    /// assert_eq!("Hello", stream.read_line().unwrap());
+    /// ```
+    ///
+    pub fn peek_n(&mut self, n: usize) -> String {
+        let mut result = String::new();
+        for i in 0..n {
+            match self.peek_nth(i) {
+                Some(c) => result.push(c),
+                None => break,
+            }
+        }
+        result
+    }
+
+    ///
+    /// whether next chars are the same as `s` without consuming.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("->1");
+    ///
+    /// assert!(stream.starts_with("->"));
+    /// assert!(!stream.starts_with("->12"));
+    /// assert_eq!(Some('-'), stream.next());
+    /// ```
+    ///
+    pub fn starts_with(&mut self, s: &str) -> bool {
+        for (i, c) in s.chars().enumerate() {
+            if self.peek_nth(i) != Some(c) {
+                return false;
+            }
+        }
+        true
+    }
+
+    ///
+    /// push back a char to the stream.
+    ///  the char will be read by next `next`, `peek`, `read_line` and `to_string`.
+    ///  the position moves back over the char.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("-1");
+    ///
+    /// let c = stream.next().unwrap();
+    /// if stream.peek().map_or(false, |c| c.is_ascii_digit()) {
+    ///     stream.unread(c);
+    /// }
+    /// assert_eq!(0, stream.position().char_offset);
+    /// assert_eq!("-1", stream.to_string());
+    /// ```
+    ///
+    pub fn unread(&mut self, c: char) {
+        // 入力にない文字を戻すと、マークへ直接戻れなくなる。
+        if self.retention.is_foreign(c) {
+            self.keep_marked_chars();
+        }
+        self.buffer.push_front(c);
+        self.retreat(c);
+    }
+
+    ///
+    /// push back a str to the stream.
+    ///  chars of the str will be read in order before the rest of the stream.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("->x");
+    ///
+    /// assert_eq!(Some('-'), stream.next());
+    /// assert_eq!(Some('>'), stream.next());
+    /// stream.push_back("->");
+    /// assert_eq!("->x", stream.to_string());
+    /// ```
+    ///
+    pub fn push_back(&mut self, s: &str) {
+        for c in s.chars().rev() {
+            self.unread(c);
+        }
+    }
+
+    ///
+    /// make a checkpoint to rewind the stream by `reset`.
+    ///  CharStream made from str, String, bytes or by `from_path_mmap` remembers the offset in its input.
+    ///  for other streams, chars read after the oldest outstanding mark are kept in memory
+    ///  until the mark is dropped or committed.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("let x = 1;");
+    ///
+    /// let mark = stream.mark();
+    /// assert_eq!("let", stream.peek_n(3));
+    /// stream.nth(3);
+    /// if stream.peek() != Some('(') {
+    ///     stream.reset(&mark);
+    /// }
+    /// stream.commit(mark);
+    ///
+    /// assert_eq!(0, stream.position().char_offset);
+    /// assert_eq!("let x = 1;", stream.to_string());
+    /// ```
+    ///
+    pub fn mark(&mut self) -> Mark {
+        self.resolve_position();
+        let offset = self.inner.offset();
+        let buffer = match offset {
+            Some(_) => self.buffer.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        self.retention.mark(self.position, self.after_cr, offset, buffer)
+    }
+
+    ///
+    /// rewind the stream to the checkpoint made by `mark`.
+    ///  the mark is still valid, so the stream can be rewound to it again.
+    ///  CharStream which remembers the offset of the mark moves there directly, without reading chars again.
+    ///  panics if the mark was made by another stream.
+    ///
+    pub fn reset(&mut self, mark: &Mark) {
+        assert!(self.retention.owns(mark), "can't reset CharStream by the mark made by another stream.");
+        self.resolve_position();
+
+        let target = mark.position();
+        // 入力の位置を覚えているマークへは、直接移動する。
+        if let Some((offset, buffer)) = mark.resume().filter(|_| !self.retention.keeps_chars()) {
+            if self.inner.move_to(offset) {
+                self.buffer = buffer.iter().cloned().collect();
+                self.lookahead.clear();
+                self.line_ends.retain(|end| end.char_offset < target.char_offset);
+                self.retention.truncate(target.char_offset);
+                self.after_cr = mark.after_cr();
+                self.position = target;
+                return;
+            }
+        }
+
+        while self.position.char_offset > target.char_offset {
+            match self.retention.last() {
+                Some(c) => self.unread(c),
+                None => break,
+            }
+        }
+        while self.position.char_offset < target.char_offset {
+            if self.next().is_none() {
+                break;
+            }
+        }
+        self.position = target;
+    }
+
+    ///
+    /// move the stream to the byte offset, which must be on a char boundary.
+    ///  this doesn't read the file. the line and the column are counted from checkpoints of the file when they are needed.
+    ///  returns `Error::UnsupportedOperation` for CharStream not made by `from_path_mmap`.
+    ///
+    pub fn seek(&mut self, byte_offset: usize) -> Result<(), Error> {
+        let after_cr = self.inner.seek(byte_offset)?;
+        self.buffer.clear();
+        self.lookahead.clear();
+        self.line_ends.clear();
+        self.after_cr = after_cr;
+        self.position = Position::new();
+        self.seek_base = Some(SeekBase { byte_offset, position: Cell::new(None) });
+        // マークと位置を比べるので、先に数えておく。
+        if self.retention.oldest().is_some() {
+            self.resolve_position();
+        }
+        Ok(())
+    }
+
+    ///
+    /// release the checkpoint made by `mark`.
+    ///  this is the same as dropping the mark.
+    ///
+    pub fn commit(&mut self, mark: Mark) {
+        drop(mark);
+        self.retention.release();
+    }
+
+    ///
+    /// str from the mark to the current position, borrowed from the str given to `from`.
+    ///  returns None for other streams, if the mark was made by another stream, or if the stream is before the mark.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let source = String::from("let 世界 = 1;");
+    /// let mut stream = CharStream::from(&source);
+    ///
+    /// stream.nth(3);
+    /// let mark = stream.mark();
+    /// while stream.peek().is_some_and(|c| c.is_alphabetic()) {
+    ///     stream.next();
+    /// }
+    /// let token: &str = stream.slice_since(&mark).unwrap();
+    /// assert_eq!("世界", token);
+    /// ```
+    ///
+    pub fn slice_since(&self, mark: &Mark) -> Option<&'a str> {
+        if !self.retention.owns(mark) {
+            return None;
+        }
+        match self.inner {
+            InternalStream::Str { ref chars } => {
+                chars.source().get(mark.position().byte_offset..self.position.byte_offset)
+            },
+            _ => None,
+        }
+    }
+
+    ///
+    /// position of the next char.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("Hello\n 世界❤");
+    /// stream.read_line();
+    /// stream.next();
+    ///
+    /// let position = stream.position();
+    /// assert_eq!(2, position.line);
+    /// assert_eq!(2, position.column);
+    /// assert_eq!(7, position.char_offset);
+    /// assert_eq!(7, position.byte_offset);
+    /// ```
+    ///
+    pub fn position(&self) -> Position {
+        match self.base_position() {
+            Some(base) => base.join(self.position),
+            None => self.position,
+        }
+    }
+
+    // `seek` で移動した位置を数えて、覚えておく。
+    fn base_position(&self) -> Option<Position> {
+        let base = self.seek_base.as_ref()?;
+        if base.position.get().is_none() {
+            base.position.set(self.inner.position_at(base.byte_offset));
+        }
+        base.position.get()
+    }
+
+    // `seek` で移動した位置からの相対的な位置を、先頭からの位置に直す。
+    fn resolve_position(&mut self) {
+        let base = match self.base_position() {
+            Some(base) => base,
+            None => return,
+        };
+        let mut first_line = true;
+        for end in self.line_ends.iter_mut() {
+            end.char_offset += base.char_offset;
+            if first_line {
+                end.column += base.column - 1;
+            }
+            first_line &= end.line_break != LineBreak::Yes;
+        }
+        self.position = base.join(self.position);
+        self.seek_base = None;
+    }
+
+    ///
+    /// read a line
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// extern crate char_stream;
+    ///
+    /// use char_stream::CharStream;
+    ///
+    /// fn main(){
+    ///     let test_data = "Hello\n 世界❤";
+    ///     let mut stream = CharStream::from(test_data);
+    ///     assert_eq!("Hello", stream.read_line().unwrap());
+    ///     assert_eq!(" 世界❤", stream.read_line().unwrap());
+    ///     assert_eq!(None, stream.next());
+    /// }
+    /// ```
+    ///
+    pub fn read_line(&mut self) -> Option<String> {
+        self.peek()?;
+
+        let mut result = String::new();
+        let endings = self.line_endings;
+
+        while let Some(c) = self.next() {
+            if c == '\n' && endings.lf() {
+                break;
+
+            } else if c == '\r' && (endings.crlf() || endings.cr()) {
+                match self.peek() {
+                    Some('\n') if endings.crlf() => {
+                        self.next();
+                        break;
+                    },
+                    None => break,
+                    _ if endings.cr() => break,
+                    _ => {},
+                }
+
+            } else if endings.is_other_break(c) {
+                break;
+            }
+
+            result.push(c);
+        }
+
+        Some(result)
+    }
+
+    ///
+    /// iterator over lines read by `read_line`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let mut stream = CharStream::from("Hello\r\n 世界❤\n");
+    /// let lines: Vec<String> = stream.lines().collect();
+    ///
+    /// assert_eq!(vec!["Hello", " 世界❤"], lines);
+    /// ```
+    ///
+    pub fn lines<'s>(&'s mut self) -> Lines<'s, 'a> {
+        Lines::new(self)
+    }
+
+    ///
+    /// set which chars end a line for `read_line`, `lines`, `lines_rev` and `position`.
+    ///  lines already read are not counted again.
+    ///
+    pub fn set_line_endings(&mut self, endings: LineEndings) {
+        self.resolve_position();
+        self.line_endings = endings;
+        self.inner.set_line_endings(endings);
+    }
+
+    ///
+    /// which chars end a line.
+    ///
+    pub fn line_endings(&self) -> LineEndings {
+        self.line_endings
+    }
+
+    ///
+    /// to string
+    ///
+    /// Example:
+    /// 
+    /// ```
+    /// use char_stream::CharStream;
+    /// 
+    /// let s = String::from("Hello 世界❤");
+    /// let mut stream = CharStream::from_string(s);
+    /// let result = stream.to_string();
+    ///
+    /// assert_eq!("Hello 世界❤", result);
+    /// ```
+    ///
+    #[allow(clippy::inherent_to_string, clippy::while_let_on_iterator)]
+    pub fn to_string(&mut self) -> String {
+        let mut string = String::new();
+
+        while let Some(c) = self.next() {
+            string.push(c);
+        }
+
+        string
+    }
+
+    ///
+    /// Convert to DoubleEndedIterator.
+    ///  caution: CharStream made by 'from_stdin' or 'follow' can't convert.
+    ///  panics if the stream can't be converted or reading it fails. see `try_wend_iter`.
+    ///  CharStream made by 'from_file' is read by blocks from the end of the file
+    ///  without loading the whole file. invalid UTF-8 in it is handled by `set_decode_mode`,
+    ///  and in `DecodeMode::Strict` the rest of the file is checked before the iterator is made.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// extern crate char_stream;
+    ///
+    /// use char_stream::CharStream;
+    ///
+    /// fn main() {
+    ///     let input = "stressed";
+    ///     let stream = CharStream::from(input);
+    ///     let mut rev_stream = stream.wend_iter().rev();
+    ///     let mut result = String::new();
+    ///     while let Some(c) = rev_stream.next() {
+    ///         result.push(c);
+    ///     }
+    ///     println!("'{}' reverse to '{}'", input , result);
+    /// }
+    /// ```
+    ///
+    pub fn wend_iter(self) -> WendIterator {
+        match self.try_wend_iter() {
+            Ok(iter) => iter,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    ///
+    /// Convert to DoubleEndedIterator.
+    ///  returns `Error::UnsupportedOperation` for CharStream made by 'from_stdin' or 'follow',
+    ///  or the error which occurred while reading the rest of the stream.
+    ///  an I/O error while reading a file by blocks ends the iterator, and is taken by `WendIterator::take_error`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Error};
+    ///
+    /// let stream = CharStream::from_stdin();
+    /// match stream.try_wend_iter() {
+    ///     Err(Error::UnsupportedOperation(_)) => (),
+    ///     _ => panic!("stdin can't be converted"),
+    /// }
+    /// ```
+    ///
+    pub fn try_wend_iter(self) -> Result<WendIterator, Error> {
+        let mut chars: Vec<char> = self.buffer.into_iter().collect();
+
+        match self.inner {
+            InternalStream::Chars { chars: mut char_vec } => {
+                if chars.is_empty() {
+                    return Ok(WendIterator::from_chars(char_vec));
+                }
+                while let Some(c) = char_vec.next() {
+                    chars.push(c);
+                }
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::Str { chars: s } => {
+                chars.extend(s.rest().chars());
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::File { file } => {
+                if !file.is_utf8() {
+                    chars.extend(file.read_and_get_all_chars()?);
+                    return Ok(WendIterator::from_chars(InternalCharVec::new(chars)));
+                }
+                let mut file = file.into_reverse_file(chars)?;
+                // 残りを一度復号して数え、不正なバイトや読み込みのエラーはここで返す。
+                file.count(self.position, self.line_endings, self.after_cr)?;
+                Ok(WendIterator::from_file(file))
+            },
+            InternalStream::Reader { reader } => {
+                chars.extend(reader.read_and_get_all_chars()?);
+                let char_vec = InternalCharVec::new(chars);
+                Ok(WendIterator::from_chars(char_vec))
+            },
+            InternalStream::Source { mut source } => {
+                while let Some(c) = source.try_next()? {
+                    chars.push(c);
+                }
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::Chain { mut chain } => {
+                while let Some(c) = chain.try_next()? {
+                    chars.push(c);
+                }
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::Include { mut include } => {
+                while let Some(c) = include.try_next()? {
+                    chars.push(c);
+                }
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::Splice { mut splice } => {
+                while let Some(c) = splice.try_next()? {
+                    chars.push(c);
+                }
+                Ok(WendIterator::from_chars(InternalCharVec::new(chars)))
+            },
+            InternalStream::StdIn { .. } => Err(Error::UnsupportedOperation("can't convert DoubleEndedIterator from CharStream made by 'from_stdin'")),
+            InternalStream::Follow { .. } => Err(Error::UnsupportedOperation("can't convert DoubleEndedIterator from CharStream made by 'follow'")),
+            InternalStream::Mmap { mut mmap } => {
+                mmap.push_front(chars);
+                mmap.count()?;
+                Ok(WendIterator::from_mmap(mmap))
+            },
+        }
+    }
+
+    ///
+    /// Convert to iterator over lines from the end of the stream.
+    ///  lines are split in the same way as `read_line`.
+    ///  CharStream made by 'from_file' is read from the end of the file,
+    ///  so the last lines of a large file can be read without reading it from the start.
+    ///  caution: panics for CharStream made by 'from_stdin' or 'follow'. see `try_lines_rev`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let stream = CharStream::from("first\r\nsecond\n\nlast\n");
+    /// let mut lines = stream.lines_rev();
+    ///
+    /// assert_eq!("last", lines.next().unwrap());
+    /// assert_eq!("", lines.next().unwrap());
+    /// assert_eq!("second", lines.next().unwrap());
+    /// assert_eq!("first", lines.next().unwrap());
+    /// assert_eq!(None, lines.next());
+    /// ```
+    ///
+    pub fn lines_rev(self) -> LinesRev {
+        match self.try_lines_rev() {
+            Ok(lines) => lines,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    ///
+    /// Convert to iterator over lines from the end of the stream.
+    ///  returns `Error::UnsupportedOperation` for CharStream made by 'from_stdin' or 'follow',
+    ///  or the error of `try_wend_iter`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::{CharStream, Error};
+    ///
+    /// let stream = CharStream::from_stdin();
+    /// match stream.try_lines_rev() {
+    ///     Err(Error::UnsupportedOperation(_)) => (),
+    ///     _ => panic!("stdin can't be converted"),
+    /// }
+    /// ```
+    ///
+    pub fn try_lines_rev(self) -> Result<LinesRev, Error> {
+        let endings = self.line_endings;
+        Ok(LinesRev::new(self.try_wend_iter()?, endings))
+    }
+
+    ///
+    /// read last n lines in order.
+    ///  caution: panics for CharStream made by 'from_stdin' or 'follow'. see `try_tail`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let stream = CharStream::from("1\n2\n3\n4");
+    ///
+    /// assert_eq!(vec!["3", "4"], stream.tail(2));
+    /// ```
+    ///
+    pub fn tail(self, n: usize) -> Vec<String> {
+        match self.try_tail(n) {
+            Ok(lines) => lines,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    ///
+    /// read last n lines in order.
+    ///  returns `Error::UnsupportedOperation` for CharStream made by 'from_stdin' or 'follow',
+    ///  or the error of `try_wend_iter`.
+    ///
+    /// Example:
+    ///
+    /// ```
+    /// use char_stream::CharStream;
+    ///
+    /// let stream = CharStream::from("1\n2\n3\n4");
+    ///
+    /// assert_eq!(vec!["3", "4"], stream.try_tail(2).unwrap());
+    /// ```
+    ///
+    pub fn try_tail(self, n: usize) -> Result<Vec<String>, Error> {
+        let mut lines: Vec<String> = self.try_lines_rev()?.take(n).collect();
+        lines.reverse();
+        Ok(lines)
+    }
+}
+
+impl<'a> Iterator for CharStream<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let result = match self.pop_buffer() {
+            Some(c) => Some(c),
+            None => self.inner.next(),
+        };
+        if let Some(c) = result {
+            self.advance(c);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
 
     use super::*;
     use std::io::prelude::*;
@@ -340,135 +1933,1917 @@ mod tests {
     use std::fs::File;
 
     #[test]
-    fn from_str() {
-        let mut stream = CharStream::from("Hello 世界❤");
+    fn from_str() {
+        let mut stream = CharStream::from("Hello 世界❤");
+
+        assert_eq!('H', stream.next().unwrap());
+        assert_eq!('e', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('o', stream.next().unwrap());
+        assert_eq!(' ', stream.next().unwrap());
+        assert_eq!('世', stream.next().unwrap());
+        assert_eq!('界', stream.next().unwrap());
+        assert_eq!('❤', stream.next().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_string() {
+        let s = String::from("Hello 世界❤");
+        let mut stream = CharStream::from_string(s);
+
+        assert_eq!('H', stream.next().unwrap());
+        assert_eq!('e', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('o', stream.next().unwrap());
+        assert_eq!(' ', stream.next().unwrap());
+        assert_eq!('世', stream.next().unwrap());
+        assert_eq!('界', stream.next().unwrap());
+        assert_eq!('❤', stream.next().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_bytes() {
+        let bytes: [u8; 15] = [72, 101, 108, 108, 111, 32, 228, 184, 150, 231, 149, 140, 226, 157, 164];
+        if let Ok(mut stream) = CharStream::from_bytes(&bytes) {
+            assert_eq!('H', stream.next().unwrap());
+            assert_eq!('e', stream.next().unwrap());
+            assert_eq!('l', stream.next().unwrap());
+            assert_eq!('l', stream.next().unwrap());
+            assert_eq!('o', stream.next().unwrap());
+            assert_eq!(' ', stream.next().unwrap());
+            assert_eq!('世', stream.next().unwrap());
+            assert_eq!('界', stream.next().unwrap());
+            assert_eq!('❤', stream.next().unwrap());
+            assert_eq!(None, stream.next());
+        }else{
+            panic!("can't convert stream from bytes.");
+        }
+    }
+
+    #[test]
+    fn from_file() {
+        let test_data = "Hello\n 世界❤";
+
+        // write test data to tempfile
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+
+        // Seek to start
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        // read test data from tempfile
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!('H', stream.next().unwrap());
+        assert_eq!('e', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('o', stream.next().unwrap());
+        assert_eq!('\n', stream.next().unwrap());
+        assert_eq!(' ', stream.next().unwrap());
+        assert_eq!('世', stream.next().unwrap());
+        assert_eq!('界', stream.next().unwrap());
+        assert_eq!('❤', stream.next().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_file_wend_iter() {
+        let test_data = "Hello\n 世界❤";
+
+        // write test data to tempfile
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+
+        // Seek to start
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        // read test data from tempfile
+        let file_stream = CharStream::from_file(tmpfile);
+        let mut stream = file_stream.wend_iter().rev();
+
+        assert_eq!('❤', stream.next().unwrap());
+        assert_eq!('界', stream.next().unwrap());
+        assert_eq!('世', stream.next().unwrap());
+        assert_eq!(' ', stream.next().unwrap());
+        assert_eq!('\n', stream.next().unwrap());
+        assert_eq!('o', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('l', stream.next().unwrap());
+        assert_eq!('e', stream.next().unwrap());
+        assert_eq!('H', stream.next().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn read_line() {
+        let test_data = "Hello\n 世界❤";
+        let mut stream = CharStream::from(test_data);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(" 世界❤", stream.read_line().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_file_read_line() {
+        let test_data = "Hello\n 世界❤";
+
+        // write test data to tempfile
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+
+        // Seek to start
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        // read test data from tempfile
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(" 世界❤", stream.read_line().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_reader() {
+        let test_data = "Hello\r\n 世界❤";
+        let cursor = io::Cursor::new(test_data.as_bytes().to_vec());
+
+        let mut stream = CharStream::from_reader(cursor);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some(' '), stream.peek());
+        assert_eq!(" 世界❤", stream.read_line().unwrap());
+        assert_eq!(None, stream.next());
+    }
+
+    fn is_send<T: Send>() {}
+
+    #[test]
+    fn send() {
+        is_send::<CharStream>();
+        is_send::<Mark>();
+        is_send::<WendIterator>();
+        is_send::<LinesRev>();
+    }
+
+    #[test]
+    fn from_reader_borrowed() {
+        let bytes = "Hello\n 世界❤".as_bytes().to_vec();
+        let mut stream = CharStream::from_reader(&bytes[..]);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(" 世界❤", stream.to_string());
+    }
+
+    struct Upper {
+        inner: CharStream<'static>,
+        position: usize,
+    }
+
+    impl CharSource for Upper {
+        fn next(&mut self) -> Option<char> {
+            let result = self.peek();
+            if result.is_some() {
+                self.inner.next();
+                self.position += 1;
+            }
+            result
+        }
+
+        fn peek(&mut self) -> Option<char> {
+            self.inner.peek().map(|c| c.to_ascii_uppercase())
+        }
+
+        fn position(&self) -> usize {
+            self.position
+        }
+    }
+
+    #[test]
+    fn from_source() {
+        let upper = Upper { inner: CharStream::from("Hello\nworld"), position: 0 };
+        let mut stream = CharStream::from_source(Box::new(upper));
+
+        assert_eq!(Some('H'), stream.peek());
+        assert_eq!("HELLO", stream.read_line().unwrap());
+        assert_eq!("WORLD", stream.to_string());
+        assert_eq!(None, stream.next());
+    }
+
+    #[test]
+    fn from_reader_wend_iter() {
+        let cursor = io::Cursor::new("stressed".as_bytes().to_vec());
+        let stream = CharStream::from_reader(cursor);
+        let result: String = stream.wend_iter().rev().collect();
+
+        assert_eq!("desserts", result);
+    }
+
+    #[test]
+    fn from_bytes_invalid_utf8() {
+        let bytes = [72, 105, 0xe4, 0xb8];
+        match CharStream::from_bytes(&bytes) {
+            Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(2, offset),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn from_file_invalid_utf8() {
+        let mut test_data = "Hello\n世界".as_bytes().to_vec();
+        test_data.push(0xff);
+        test_data.extend_from_slice("❤\n".as_bytes());
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&test_data).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some('世'), stream.try_next().unwrap());
+        assert_eq!(Some('界'), stream.try_peek().unwrap());
+        assert_eq!(Some('界'), stream.try_next().unwrap());
+        match stream.try_peek() {
+            Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(12, offset),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+        assert_eq!(None, stream.try_next().unwrap());
+    }
+
+    #[test]
+    fn malformed_line_endings() {
+        let line = |endings: Option<LineEndings>| {
+            let mut stream = CharStream::from_reader(io::Cursor::new(b"a\rb\r\nc\xff".to_vec()));
+            if let Some(endings) = endings {
+                stream.set_line_endings(endings);
+            }
+            assert_eq!("a\rb\r\nc", stream.by_ref().collect::<String>());
+            let position = stream.position();
+            match stream.try_next() {
+                Err(Error::InvalidUtf8 { line, .. }) => assert_eq!(position.line, line),
+                _ => panic!("invalid utf8 must be an error."),
+            }
+            position.line
+        };
+        assert_eq!(2, line(None));
+        assert_eq!(3, line(Some(LineEndings::AnyOf { lf: true, crlf: true, cr: true })));
+
+        match CharStream::from_bytes(b"a\r\nb\rc\xff") {
+            Err(Error::InvalidUtf8 { line, .. }) => assert_eq!(2, line),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+
+        // U+FFFD に置き換えたバイトを挟む "\r" と "\n" は、別々の改行になる。
+        let mut stream = CharStream::from_reader(OneByteReader::new(b"a\r\xff\nb\xfe"));
+        stream.set_line_endings(LineEndings::AnyOf { lf: true, crlf: true, cr: true });
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!("a\r\u{fffd}\nb", stream.by_ref().take(5).collect::<String>());
+        assert_eq!(3, stream.position().line);
+        stream.set_decode_mode(DecodeMode::Strict);
+        match stream.try_next() {
+            Err(Error::InvalidUtf8 { line, .. }) => assert_eq!(3, line),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn skip_malformed() {
+        let mut test_data = "Hello\n世界".as_bytes().to_vec();
+        test_data.extend_from_slice(&[0xff, 0xe2, 0x9d, b'\n', b'a', 0xed, 0xa0, 0x80, b'b']);
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&test_data).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert!(!stream.skip_malformed());
+        assert_eq!("Hello", stream.read_line().unwrap());
+        let mut chars = String::new();
+        let mut errors = Vec::new();
+        loop {
+            match stream.try_next() {
+                Ok(Some(c)) => chars.push(c),
+                Ok(None) => break,
+                Err(Error::InvalidUtf8 { offset, line, bytes }) => {
+                    errors.push((offset, line, bytes));
+                    assert!(stream.skip_malformed());
+                },
+                Err(err) => panic!("{}", err),
+            }
+        }
+        assert_eq!("世界\nab", chars);
+        assert_eq!(vec![(12, 2, vec![0xff]), (13, 2, vec![0xe2, 0x9d]), (17, 3, vec![0xed]), (18, 3, vec![0xa0]), (19, 3, vec![0x80])], errors);
+        assert!(!stream.skip_malformed());
+    }
+
+    #[test]
+    fn malformed_error_details() {
+        match CharStream::from_bytes(b"a\nb\nc\xe4\xb8") {
+            Err(err) => {
+                assert_eq!("invalid UTF-8 sequence [e4 b8] at line 3, byte offset 5", err.to_string());
+                match err {
+                    Error::InvalidUtf8 { offset, line, bytes } => assert_eq!((5, 3, vec![0xe4, 0xb8]), (offset, line, bytes)),
+                    _ => panic!("invalid utf8 must be an error."),
+                }
+            },
+            Ok(_) => panic!("invalid utf8 must be an error."),
+        }
+
+        // 上位サロゲートは前の行で読んだバイトに含まれる。
+        let bytes = [0x61, 0x00, 0x0a, 0x00, 0x3d, 0xd8, 0x0a, 0x00, 0x62, 0x00];
+        let mut stream = CharStream::from_reader_with_encoding(io::Cursor::new(bytes.to_vec()), Encoding::Utf16Le);
+        assert_eq!("a", stream.read_line().unwrap());
+        match stream.try_next() {
+            Err(Error::Malformed { encoding, offset, line, bytes }) => {
+                assert_eq!((Encoding::Utf16Le, 4, 2, vec![0x3d, 0xd8]), (encoding, offset, line, bytes));
+            },
+            _ => panic!("unpaired surrogate must be an error."),
+        }
+        assert!(stream.skip_malformed());
+        assert_eq!("\nb", stream.to_string());
+    }
+
+    #[test]
+    fn borrowed_tokens() {
+        let source = String::from("foo  世界\n❤bar");
+        let tokens = {
+            let mut stream = CharStream::from(&source);
+            let mut tokens: Vec<&str> = Vec::new();
+            loop {
+                while stream.peek().is_some_and(|c| c.is_whitespace()) {
+                    stream.next();
+                }
+                let mark = stream.mark();
+                while stream.peek().is_some_and(|c| !c.is_whitespace()) {
+                    stream.next();
+                }
+                match stream.slice_since(&mark) {
+                    Some("") | None => break,
+                    Some(token) => tokens.push(token),
+                }
+            }
+            tokens
+        };
+        assert_eq!(vec!["foo", "世界", "❤bar"], tokens);
+
+        let mut stream = CharStream::from(&source);
+        let mark = stream.mark();
+        stream.nth(6);
+        let later = stream.mark();
+        assert_eq!(Some("foo  世界"), stream.slice_since(&mark));
+        stream.reset(&mark);
+        assert_eq!(None, stream.slice_since(&later));
+        assert_eq!(None, CharStream::from("foo").slice_since(&mark));
+
+        let mut stream = CharStream::from_string(source.clone());
+        let mark = stream.mark();
+        stream.next();
+        assert_eq!(None, stream.slice_since(&mark));
+    }
+
+    #[test]
+    fn chunked_reading() {
+        let text = "Hello\r\n 世界❤\n😀𝄞".repeat(3);
+        let utf16 = encode_utf16(&text, true);
+        for size in 1..9 {
+            let mut tmpfile: File = tempfile::tempfile().unwrap();
+            tmpfile.write_all(text.as_bytes()).unwrap();
+            tmpfile.seek(SeekFrom::Start(0)).unwrap();
+            let mut stream = CharStream::from_file(tmpfile);
+            stream.set_buffer_size(size);
+            assert_eq!(text, stream.to_string());
+
+            let mut stream = CharStream::from_reader_with_encoding(io::Cursor::new(utf16.clone()), Encoding::Utf16Be);
+            stream.set_buffer_size(size);
+            assert_eq!(text, stream.to_string());
+        }
+
+        let mut bytes = "a".repeat(10).into_bytes();
+        bytes.push(0xff);
+        let mut stream = CharStream::from_reader(io::Cursor::new(bytes));
+        stream.set_buffer_size(3);
+        assert_eq!("aaaaaaaaaa", stream.to_string());
+        match stream.try_next() {
+            Err(Error::InvalidUtf8 { offset, line, .. }) => assert_eq!((10, 1), (offset, line)),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn chunked_reading_wend_iter() {
+        // 読み込んだチャンクの末尾で切れた文字も、後ろから読める。
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all("世界❤stressed".as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        stream.set_buffer_size(4);
+        assert_eq!(Some('世'), stream.next());
+        let result: String = stream.wend_iter().rev().collect();
+        assert_eq!("desserts❤界", result);
+    }
+
+    #[test]
+    fn try_next_clean_eof() {
+        let cursor = io::Cursor::new("ab".as_bytes().to_vec());
+        let mut stream = CharStream::from_reader(cursor);
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        assert_eq!(Some('b'), stream.try_next().unwrap());
+        assert_eq!(None, stream.try_next().unwrap());
+    }
+
+    #[test]
+    fn try_wend_iter_invalid_utf8() {
+        let cursor = io::Cursor::new(vec![b'a', 0xc0, b'b']);
+        let stream = CharStream::from_reader(cursor);
+        match stream.try_wend_iter() {
+            Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(1, offset),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn position() {
+        let mut stream = CharStream::from("ab\r\n世界\n\n❤");
+        assert_eq!(Position::new(), stream.position());
+
+        stream.next();
+        stream.next();
+        let position = stream.position();
+        assert_eq!((1, 3, 2, 2), (position.line, position.column, position.char_offset, position.byte_offset));
+
+        assert_eq!(Some('\r'), stream.next());
+        assert_eq!(Some('\n'), stream.next());
+        let position = stream.position();
+        assert_eq!((2, 1, 4, 4), (position.line, position.column, position.char_offset, position.byte_offset));
+
+        assert_eq!("世界", stream.read_line().unwrap());
+        assert_eq!("", stream.read_line().unwrap());
+        let position = stream.position();
+        assert_eq!((4, 1, 8, 12), (position.line, position.column, position.char_offset, position.byte_offset));
+
+        assert_eq!(Some('❤'), stream.try_next().unwrap());
+        assert_eq!("4:2", stream.position().to_string());
+    }
+
+    #[test]
+    fn from_file_position() {
+        let test_data = "Hello\r\n 世界❤";
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some(' '), stream.next());
+        assert_eq!(Some('世'), stream.next());
+        let position = stream.position();
+        assert_eq!((2, 3, 9, 11), (position.line, position.column, position.char_offset, position.byte_offset));
+    }
+
+    #[test]
+    fn from_file_peek_nth() {
+        let test_data = "ab\ncd\n\nef";
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!(Some('a'), stream.next());
+        assert_eq!(Some('e'), stream.peek_nth(6));
+        assert_eq!("b\ncd\n\nef", stream.peek_n(10));
+        assert!(stream.starts_with("b\ncd"));
+        assert_eq!(Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }, stream.position());
+
+        assert_eq!("b", stream.read_line().unwrap());
+        assert_eq!("cd", stream.read_line().unwrap());
+        assert_eq!(Some('\n'), stream.peek());
+        assert_eq!(3, stream.position().line);
+        assert_eq!("\nef", stream.to_string());
+        assert_eq!(None, stream.peek_nth(0));
+    }
+
+    #[test]
+    fn peek_nth_wend_iter() {
+        let mut stream = CharStream::from("stressed");
+        assert_eq!(Some('s'), stream.next());
+        assert_eq!(Some('d'), stream.peek_nth(6));
+
+        let result: String = stream.wend_iter().rev().collect();
+        assert_eq!("dessert", result);
+    }
+
+    #[test]
+    fn peek_nth_try_next_error() {
+        let cursor = io::Cursor::new(vec![b'a', b'\n', 0xff]);
+        let mut stream = CharStream::from_reader(cursor);
+        assert_eq!("a\n", stream.peek_n(5));
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        assert_eq!(Some('\n'), stream.try_next().unwrap());
+        assert!(stream.try_next().is_err());
+    }
+
+    #[test]
+    fn from_file_unread() {
+        let test_data = "a\nb";
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!("a", stream.read_line().unwrap());
+        assert_eq!(Position { line: 2, column: 1, char_offset: 2, byte_offset: 2 }, stream.position());
+
+        stream.unread('\n');
+        assert_eq!(Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }, stream.position());
+        stream.push_back("世界");
+        assert_eq!(Some('世'), stream.peek());
+        assert_eq!(Position { line: 1, column: 1, char_offset: 0, byte_offset: 0 }, stream.position());
+
+        assert_eq!("世界", stream.read_line().unwrap());
+        assert_eq!(Position { line: 2, column: 1, char_offset: 3, byte_offset: 7 }, stream.position());
+        assert_eq!(Some('b'), stream.next());
+        assert_eq!(None, stream.next());
+
+        stream.unread('c');
+        assert_eq!("c", stream.to_string());
+    }
+
+    #[test]
+    fn unread_wend_iter() {
+        let mut stream = CharStream::from("ssert");
+        stream.push_back("stre");
+        stream.unread('d');
+
+        let result: String = stream.wend_iter().rev().collect();
+        assert_eq!("tressertsd", result);
+    }
+
+    #[test]
+    fn from_file_mark_reset() {
+        let test_data = "ab\ncd\nef";
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data.as_bytes()).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut stream = CharStream::from_file(tmpfile);
+        assert_eq!(Some('a'), stream.next());
+
+        let outer = stream.mark();
+        assert_eq!("b", stream.read_line().unwrap());
+        let inner = stream.mark();
+        assert_eq!("cd", stream.read_line().unwrap());
+        assert_eq!(Some('e'), stream.next());
+
+        stream.reset(&inner);
+        assert_eq!(Position { line: 2, column: 1, char_offset: 3, byte_offset: 3 }, stream.position());
+        assert_eq!("cd", stream.read_line().unwrap());
+
+        stream.reset(&outer);
+        assert_eq!(Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }, stream.position());
+        stream.commit(outer);
+        assert_eq!("b", stream.read_line().unwrap());
+        assert_eq!("cd", stream.read_line().unwrap());
+
+        stream.reset(&inner);
+        drop(inner);
+        assert_eq!("cd\nef", stream.to_string());
+    }
+
+    #[test]
+    fn mark_unread_reset() {
+        let mut stream = CharStream::from("abc");
+        let mark = stream.mark();
+        assert_eq!(Some('a'), stream.next());
+        assert_eq!(Some('b'), stream.next());
+        stream.unread('x');
+        assert_eq!(Some('x'), stream.next());
+        assert_eq!(Some('c'), stream.next());
+
+        stream.reset(&mark);
+        assert_eq!("axc", stream.to_string());
+
+        stream.reset(&mark);
+        assert_eq!(Position::new(), stream.position());
+        assert_eq!("axc", stream.to_string());
+    }
+
+    #[test]
+    fn mark_reset_in_place() {
+        let text = "ab\r\n世界".repeat(1000);
+        let mut stream = CharStream::from(&text);
+        stream.nth(2);
+        assert_eq!("\n世", stream.peek_n(2));
+        let mark = stream.mark();
+        stream.nth(4999);
+        assert!(!stream.retention.keeps_chars());
+        assert_eq!(None, stream.retention.last());
+
+        stream.reset(&mark);
+        assert_eq!(Position { line: 1, column: 4, char_offset: 3, byte_offset: 3 }, stream.position());
+        assert_eq!(Some('\n'), stream.next());
+        assert_eq!(Position { line: 2, column: 1, char_offset: 4, byte_offset: 4 }, stream.position());
+
+        // 読んだ文字を戻しても、入力と同じなら文字を覚えない。
+        let mut stream = CharStream::from_string(text.clone());
+        let mark = stream.mark();
+        assert_eq!(Some('a'), stream.next());
+        stream.unread('a');
+        stream.nth(5);
+        assert!(!stream.retention.keeps_chars());
+        stream.reset(&mark);
+        assert_eq!(text, stream.to_string());
+
+        // 入力にない文字を戻すと、マークから読んだ文字を覚える。
+        stream.reset(&mark);
+        stream.nth(1);
+        stream.push_back("xy");
+        assert!(stream.retention.keeps_chars());
+        stream.reset(&mark);
+        assert_eq!("xy\r\n世界ab", stream.peek_n(8));
+        stream.commit(mark);
+        stream.mark();
+        assert!(!stream.retention.keeps_chars());
+    }
+
+    #[test]
+    fn mark_released() {
+        let text = "a".repeat(5000);
+        let mut stream = CharStream::from_string(text);
+
+        let old = stream.mark();
+        stream.nth(2999);
+        let mark = stream.mark();
+        drop(old);
+        stream.nth(1499);
+        assert_eq!(4500, stream.position().char_offset);
+
+        stream.reset(&mark);
+        assert_eq!(3000, stream.position().char_offset);
+        assert_eq!(2000, stream.by_ref().count());
+    }
+
+    #[test]
+    #[should_panic]
+    fn reset_by_foreign_mark() {
+        let mut stream1 = CharStream::from("abc");
+        let mut stream2 = CharStream::from("abc");
+        let mark = stream1.mark();
+        stream2.reset(&mark);
+    }
+
+    #[test]
+    fn open_path() {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        tmpfile.write_all(&[b'a', b'\n', b'b', 0xff]).unwrap();
+
+        let mut stream = CharStream::open(tmpfile.path()).unwrap();
+        assert_eq!(Some(tmpfile.path()), stream.path());
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        assert_eq!(Some('\n'), stream.try_next().unwrap());
+        assert_eq!(Some('b'), stream.try_next().unwrap());
+        match stream.try_next() {
+            Err(err @ Error::InFile { .. }) => {
+                assert_eq!(format!("{}: invalid UTF-8 sequence [ff] at line 2, byte offset 3", tmpfile.path().display()), err.to_string());
+                match err {
+                    Error::InFile { path, error } => {
+                        assert_eq!(tmpfile.path(), path.as_path());
+                        assert!(matches!(*error, Error::InvalidUtf8 { offset: 3, line: 2, .. }));
+                    },
+                    _ => unreachable!(),
+                }
+            },
+            _ => panic!("invalid UTF-8 should be an error with the path"),
+        }
+        assert_eq!(format!("{}:2:2", tmpfile.path().display()), stream.location());
+        assert_eq!(None, CharStream::from("").path());
+    }
+
+    #[test]
+    fn open_stdin() {
+        let stream = CharStream::open("-").unwrap();
+        assert_eq!(Some(Path::new("-")), stream.path());
+        match stream.try_wend_iter() {
+            Err(Error::UnsupportedOperation(_)) => (),
+            _ => panic!("stdin can't be converted"),
+        }
+
+        match CharStream::open("no/such/file") {
+            Err(Error::InFile { path, error }) => {
+                assert_eq!(Path::new("no/such/file"), path.as_path());
+                assert!(matches!(*error, Error::Io(ref err) if err.kind() == io::ErrorKind::NotFound));
+            },
+            _ => panic!("the file doesn't exist"),
+        }
+    }
+
+    #[test]
+    fn chain_boundaries() {
+        let sources = || vec![CharStream::from("a"), CharStream::from(""), CharStream::from("b\n"), CharStream::from("c")];
+        assert_eq!("ab\nc", CharStream::chain(sources()).to_string());
+
+        let mut stream = CharStream::chain(sources());
+        stream.set_insert_newline(true);
+        assert_eq!(Some(0), stream.source_index());
+        assert_eq!(Some('a'), stream.next());
+        assert_eq!(Some('\n'), stream.peek());
+        assert_eq!(Some(0), stream.source_index());
+        assert_eq!(Some(Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }), stream.source_position());
+        assert_eq!(Some('\n'), stream.next());
+        assert_eq!(Some('b'), stream.peek());
+        assert_eq!(Some(2), stream.source_index());
+        assert_eq!(Some(Position::new()), stream.source_position());
+        assert_eq!("b\nc", stream.to_string());
+        assert_eq!(Some(3), stream.source_index());
+        assert_eq!(Position { line: 3, column: 2, char_offset: 5, byte_offset: 5 }, stream.position());
+
+        let sources = vec![CharStream::from_bytes_with_mode(b"a\xff", Encoding::Utf8, DecodeMode::Lossy).unwrap(), CharStream::from("b")];
+        let mut stream = CharStream::chain(sources);
+        assert_eq!("a\u{fffd}b", stream.by_ref().collect::<String>());
+        assert_eq!(1, stream.replacements());
+
+        assert_eq!(None, CharStream::chain(Vec::new()).next());
+        assert_eq!(None, CharStream::chain(Vec::new()).source_index());
+        assert_eq!(None, CharStream::from("a").source_index());
+    }
+
+    #[test]
+    fn chain_lookahead() {
+        let mut stream = CharStream::chain(vec![CharStream::from("abcdef")]);
+        stream.next();
+        assert!(stream.starts_with("bcde"));
+        assert_eq!("1:2", stream.location());
+
+        let mut stream = CharStream::chain(vec![CharStream::from("a\nb"), CharStream::from("cd")]);
+        assert_eq!("a\nbc", stream.peek_n(4));
+        assert_eq!(Some(0), stream.source_index());
+        assert_eq!("1:1", stream.location());
+        stream.nth(2);
+        assert_eq!(Some(1), stream.source_index());
+        assert_eq!("1:1", stream.location());
+        stream.unread('x');
+        assert_eq!("1:1", stream.location());
+        assert_eq!("xcd", stream.to_string());
+        assert_eq!("1:3", stream.location());
+    }
+
+    #[test]
+    fn chain_error() {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        tmpfile.write_all(&[b'a', 0xff, b'b']).unwrap();
+
+        let sources = vec![CharStream::open(tmpfile.path()).unwrap(), CharStream::from("c")];
+        let mut stream = CharStream::chain(sources);
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        assert_eq!(format!("{}:1:2", tmpfile.path().display()), stream.location());
+        assert_eq!(None, stream.next());
+        match stream.try_next() {
+            Err(Error::InFile { ref path, .. }) => assert_eq!(tmpfile.path(), path.as_path()),
+            _ => panic!("invalid UTF-8 should be an error with the path"),
+        }
+        assert_eq!(Some('c'), stream.try_next().unwrap());
+        assert_eq!(None, stream.try_next().unwrap());
+
+        let sources = vec![CharStream::open(tmpfile.path()).unwrap(), CharStream::from("c")];
+        let mut stream = CharStream::chain(sources);
+        assert_eq!(Some('a'), stream.next());
+        assert!(stream.try_peek().is_err());
+        assert!(stream.skip_malformed());
+        assert_eq!("bc", stream.to_string());
+
+        let mut stream = CharStream::from_paths(&[tmpfile.path(), tmpfile.path()]).unwrap();
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!("a\u{fffd}ba\u{fffd}b", stream.to_string());
+        assert_eq!(2, stream.replacements());
+
+        match CharStream::from_paths(&[tmpfile.path(), Path::new("no/such/file")]) {
+            Err(Error::InFile { ref path, .. }) => assert_eq!(Path::new("no/such/file"), path.as_path()),
+            _ => panic!("the file doesn't exist"),
+        }
+    }
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn include_dir(files: &[(&str, &str)]) -> TempDir {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let dir = TempDir(tmpfile.path().with_extension("d"));
+        for &(name, text) in files {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().write_all(text.as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn include_nested() {
+        let dir = include_dir(&[("main.txt", "a @inc/b.txt!z"), ("inc/b.txt", "b\n@c.txt!y"), ("inc/c.txt", "c")]);
+        let main = dir.path().join("main.txt");
+        let mut stream = CharStream::with_includes(CharStream::open(&main).unwrap());
+
+        let mut result = String::new();
+        while let Some(c) = stream.next() {
+            if c != '@' {
+                result.push(c);
+                continue;
+            }
+            let mut path = String::new();
+            for c in stream.by_ref() {
+                if c == '!' {
+                    break;
+                }
+                path.push(c);
+            }
+            // 先読みした文字は、インクルードしたファイルの後に読む。
+            stream.unread('#');
+            stream.include(&path).unwrap();
+            if path == "c.txt" {
+                let chain = stream.include_chain();
+                assert_eq!(3, chain.len());
+                assert_eq!((Some(main.as_path()), Position { line: 1, column: 13, char_offset: 12, byte_offset: 12 }), chain[0]);
+                assert_eq!(Some(dir.path().join("inc/b.txt").as_path()), chain[1].0);
+                assert_eq!(Position { line: 2, column: 7, char_offset: 8, byte_offset: 8 }, chain[1].1);
+                assert_eq!(Some(dir.path().join("inc/c.txt").as_path()), stream.source_path());
+                assert_eq!(format!("{}:1:1", dir.path().join("inc/c.txt").display()), stream.location());
+            }
+        }
+        assert_eq!("a b\nc#y#z", result);
+        assert_eq!(1, stream.include_chain().len());
+        assert_eq!(Some(Position { line: 1, column: 15, char_offset: 14, byte_offset: 14 }), stream.source_position());
+    }
+
+    #[test]
+    fn include_lookahead() {
+        let dir = include_dir(&[("main.txt", "ab\ncd\nef\n"), ("sub.txt", "x\ny")]);
+        let main = dir.path().join("main.txt");
+        let mut stream = CharStream::with_includes(CharStream::open(&main).unwrap());
+        assert_eq!("ab\ncd\nef", stream.peek_n(8));
+        assert_eq!(format!("{}:1:1", main.display()), stream.location());
+
+        stream.nth(3);
+        stream.include("sub.txt").unwrap();
+        assert_eq!(Some('x'), stream.next());
+        assert_eq!("\nyd", stream.peek_n(3));
+        assert_eq!(format!("{}:1:2", dir.path().join("sub.txt").display()), stream.location());
+        let chain = stream.include_chain();
+        assert_eq!(Position { line: 2, column: 2, char_offset: 4, byte_offset: 4 }, chain[0].1);
+        assert_eq!((Some(dir.path().join("sub.txt").as_path()), Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }), chain[1]);
+        stream.nth(1);
+        assert_eq!(format!("{}:2:2", main.display()), stream.location());
+        assert_eq!(1, stream.include_chain().len());
+    }
+
+    #[test]
+    fn include_decode_mode() {
+        let dir = include_dir(&[("main.txt", "ab")]);
+        File::create(dir.path().join("bad.txt")).unwrap().write_all(b"x\xffy").unwrap();
+        let mut stream = CharStream::with_includes(CharStream::open(dir.path().join("main.txt")).unwrap());
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!(Some('a'), stream.next());
+        stream.include("bad.txt").unwrap();
+        assert_eq!("x\u{fffd}yb", stream.to_string());
+        assert_eq!(1, stream.replacements());
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = include_dir(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+        let a = dir.path().join("a.txt");
+        let mut stream = CharStream::with_includes(CharStream::open(&a).unwrap());
+        stream.include("sub/b.txt").unwrap();
+        match stream.include("../a.txt") {
+            Err(Error::IncludeCycle { ref path, ref chain }) => {
+                assert_eq!(dir.path().join("sub/../a.txt"), *path);
+                assert_eq!(vec![a.clone(), dir.path().join("sub/b.txt")], *chain);
+            },
+            _ => panic!("a.txt is included recursively"),
+        }
+        match stream.include("no_such_file") {
+            Err(Error::InFile { ref path, .. }) => assert_eq!(dir.path().join("sub/no_such_file"), *path),
+            _ => panic!("the file doesn't exist"),
+        }
+        assert_eq!("ba", stream.to_string());
+
+        // ファイルを読み終えた後は、もう一度インクルードできる。
+        stream.include("sub/b.txt").unwrap();
+        assert_eq!("b", stream.to_string());
+
+        match CharStream::from("a").include(&a) {
+            Err(Error::UnsupportedOperation(_)) => (),
+            _ => panic!("str can't include a file"),
+        }
+        assert!(CharStream::from("a").include_chain().is_empty());
+    }
+
+    #[test]
+    fn splice_nested() {
+        let mut stream = CharStream::from("a\nA b");
+        assert_eq!("a", stream.read_line().unwrap());
+        assert_eq!(Some('A'), stream.next());
+        assert_eq!(Origin::Original, stream.origin());
+        stream.splice_str("x\nB").unwrap();
+        assert_eq!("x", stream.read_line().unwrap());
+        assert_eq!(Origin::Expansion { depth: 1 }, stream.origin());
+        assert_eq!(Some('B'), stream.next());
+
+        // 展開の最後の文字を読んだ直後に展開すると、その中に入れ子になる。
+        stream.splice(CharStream::from("y")).unwrap();
+        assert_eq!(Some('y'), stream.peek());
+        stream.splice_str("z").unwrap();
+        assert_eq!(Some('z'), stream.next());
+        assert_eq!(Origin::Expansion { depth: 3 }, stream.origin());
+        assert_eq!(Some('y'), stream.next());
+        assert_eq!(Origin::Expansion { depth: 2 }, stream.origin());
+        assert_eq!(Some(' '), stream.next());
+        assert_eq!(Origin::Original, stream.origin());
+        assert_eq!("b", stream.to_string());
+        assert_eq!(Some(Position { line: 2, column: 4, char_offset: 5, byte_offset: 5 }), stream.source_position());
+        assert_eq!(Position { line: 3, column: 6, char_offset: 10, byte_offset: 10 }, stream.position());
+
+        stream.splice_str("").unwrap();
+        assert_eq!(None, stream.next());
+        assert_eq!(Origin::Original, stream.origin());
+    }
+
+    #[test]
+    fn splice_unread() {
+        let mut stream = CharStream::from("ab");
+        assert_eq!(Some('a'), stream.next());
+        assert_eq!(Some('b'), stream.next());
+        stream.unread('b');
+        stream.splice_str("12").unwrap();
+        assert_eq!(Some('1'), stream.next());
+        stream.unread('1');
+        stream.splice_str("3").unwrap();
+        assert_eq!("312b", stream.to_string());
+        assert_eq!(Some(Position { line: 1, column: 3, char_offset: 2, byte_offset: 2 }), stream.source_position());
+
+        let mut stream = CharStream::from("ab");
+        let mark = stream.mark();
+        stream.next();
+        stream.splice_str("x").unwrap();
+        assert_eq!(Some('x'), stream.next());
+        stream.reset(&mark);
+        assert_eq!("axb", stream.to_string());
+    }
+
+    #[test]
+    fn splice_lookahead() {
+        let mut stream = CharStream::from("hello world");
+        stream.next();
+        stream.splice_str("X").unwrap();
+        assert_eq!(Some('X'), stream.next());
+        assert_eq!("ello ", stream.peek_n(5));
+        assert_eq!("1:2", stream.location());
+
+        // 展開を読む前に先読みしても、元の入力の位置は変わらない。
+        assert_eq!(Some('e'), stream.next());
+        assert_eq!("llo", stream.peek_n(3));
+        stream.splice_str("YZ").unwrap();
+        assert_eq!("YZl", stream.peek_n(3));
+        assert_eq!("1:3", stream.location());
+        assert_eq!("YZllo world", stream.to_string());
+        assert_eq!("1:12", stream.location());
+    }
+
+    #[test]
+    fn splice_in_file_error() {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        tmpfile.write_all(b"a\xff").unwrap();
+        let mut stream = CharStream::open(tmpfile.path()).unwrap();
+        stream.splice(CharStream::from("X")).unwrap();
+        assert_eq!(Some(tmpfile.path()), stream.path());
+        assert_eq!(Some('X'), stream.try_next().unwrap());
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        match stream.try_next() {
+            Err(Error::InFile { ref path, ref error }) => {
+                assert_eq!(tmpfile.path(), path.as_path());
+                match **error {
+                    Error::InvalidUtf8 { .. } => (),
+                    _ => panic!("the path must be added only once."),
+                }
+            },
+            _ => panic!("invalid utf8 must be an error."),
+        }
+
+        let mut stream = CharStream::open(tmpfile.path()).unwrap();
+        stream.splice(CharStream::from("X")).unwrap();
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!("Xa\u{fffd}", stream.to_string());
+        assert_eq!(1, stream.replacements());
+
+        // 展開したファイルのエラーには、そのファイル名だけを付ける。
+        let mut stream = CharStream::open(tmpfile.path()).unwrap();
+        assert_eq!(Some('a'), stream.next());
+        let mut main = CharStream::from("b");
+        main.splice(stream).unwrap();
+        match main.try_next() {
+            Err(Error::InFile { ref path, ref error }) => {
+                assert_eq!(tmpfile.path(), path.as_path());
+                match **error {
+                    Error::InvalidUtf8 { .. } => (),
+                    _ => panic!("the path must be added only once."),
+                }
+            },
+            _ => panic!("invalid utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn splice_limit() {
+        let mut stream = CharStream::from("M");
+        stream.set_splice_limit(3);
+        let mut count = 0;
+        let result = loop {
+            match stream.next() {
+                Some('M') => {
+                    count += 1;
+                    if let Err(err) = stream.splice_str("M") {
+                        break err;
+                    }
+                },
+                _ => panic!("M is expanded recursively"),
+            }
+        };
+        match result {
+            Error::SpliceLimit { limit } => assert_eq!(3, limit),
+            err => panic!("{}", err),
+        }
+        assert_eq!(4, count);
+        assert_eq!("splice is nested deeper than 3", Error::SpliceLimit { limit: 3 }.to_string());
+
+        let mut stream = CharStream::from("M");
+        stream.set_splice_limit(0);
+        assert!(stream.splice_str("x").is_err());
+        assert_eq!("M", stream.to_string());
+    }
+
+    fn mmap_file(test_data: &[u8]) -> (tempfile::NamedTempFile, Result<CharStream<'static>, Error>) {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        tmpfile.write_all(test_data).unwrap();
+        let stream = CharStream::from_path_mmap(tmpfile.path());
+        (tmpfile, stream)
+    }
+
+    #[test]
+    fn from_path_mmap() {
+        let test_data = "Hello\n 世界❤\n𝄞";
+        let (tmpfile, stream) = mmap_file(test_data.as_bytes());
+        let mut stream = stream.unwrap();
+        assert_eq!(Some(tmpfile.path()), stream.path());
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some(' '), stream.next());
+        assert_eq!(format!("{}:2:2", tmpfile.path().display()), stream.location());
+        assert_eq!(Position { line: 2, column: 2, char_offset: 7, byte_offset: 7 }, stream.position());
+        assert_eq!("世界❤\n𝄞", stream.to_string());
+
+        let (_tmpfile, stream) = mmap_file(b"");
+        assert_eq!(None, stream.unwrap().next());
+    }
+
+    #[test]
+    fn from_path_mmap_invalid_utf8() {
+        let (tmpfile, stream) = mmap_file(&[b'a', b'\r', b'b', 0xff, b'c', b'\n', 0xe4, 0xb8]);
+        let mut stream = stream.unwrap();
+        stream.set_line_endings(LineEndings::AnyOf { lf: true, crlf: true, cr: true });
+        assert_eq!("a\rb", stream.by_ref().collect::<String>());
+        match stream.try_next() {
+            Err(Error::InFile { ref path, ref error }) => {
+                assert_eq!(tmpfile.path(), path.as_path());
+                match **error {
+                    Error::InvalidUtf8 { offset, line, ref bytes } => assert_eq!((3, 2, vec![0xff]), (offset, line, bytes.clone())),
+                    _ => panic!("invalid UTF-8 should be an error"),
+                }
+            },
+            _ => panic!("the error should have the path"),
+        }
+        assert!(stream.skip_malformed());
+        assert_eq!(Some('c'), stream.next());
+        match stream.try_wend_iter() {
+            Err(Error::InvalidUtf8 { offset, line, .. }) => assert_eq!((6, 3), (offset, line)),
+            _ => panic!("the rest is not valid UTF-8"),
+        }
+    }
+
+    #[test]
+    fn from_path_mmap_decode_mode() {
+        let test_data = [b'a', b'\r', 0xff, b'\n', 0xe4, 0xb8, b'b', 0x80, 0x80, b'c'];
+        let open = |mode| {
+            let (tmpfile, stream) = mmap_file(&test_data);
+            let mut stream = stream.unwrap();
+            stream.set_decode_mode(mode);
+            (tmpfile, stream)
+        };
+
+        let expected = String::from_utf8_lossy(&test_data).to_string();
+        let (_tmpfile, mut stream) = open(DecodeMode::Lossy);
+        assert_eq!(expected, stream.to_string());
+        assert_eq!(4, stream.replacements());
+        assert_eq!(Position { line: 2, column: 6, char_offset: 9, byte_offset: 10 }, stream.position());
+        let (_tmpfile, stream) = open(DecodeMode::Lossy);
+        let iter = stream.wend_iter();
+        assert_eq!(9, iter.len());
+        assert_eq!(expected.chars().rev().collect::<String>(), iter.rev().collect::<String>());
+
+        // 読み飛ばしたバイトを挟む "\r" と "\n" は、ひとつの改行になる。
+        let (_tmpfile, mut stream) = open(DecodeMode::Skip);
+        assert_eq!("a\r\nbc", stream.to_string());
+        assert_eq!(4, stream.replacements());
+        assert_eq!(Position { line: 2, column: 3, char_offset: 5, byte_offset: 10 }, stream.position());
+        let (_tmpfile, stream) = open(DecodeMode::Skip);
+        let mut iter = stream.wend_iter();
+        assert_eq!(5, iter.len());
+        assert_eq!(Some('c'), iter.next_back());
+        assert_eq!(Some('b'), iter.next_back());
+        assert_eq!(Some('a'), iter.next());
+        assert_eq!("\r\n", iter.collect::<String>());
+    }
+
+    #[test]
+    fn from_path_mmap_byte_offset() {
+        // 読み進めた位置と seek した位置は、どちらも不正なバイトを含めたファイルのオフセットになる。
+        let test_data = [b'a', 0xff, b'b', 0xe4, 0xb8, b'\n', b'c'];
+        for &mode in [DecodeMode::Strict, DecodeMode::Lossy, DecodeMode::Skip].iter() {
+            let (_tmpfile, stream) = mmap_file(&test_data);
+            let mut stream = stream.unwrap();
+            stream.set_decode_mode(mode);
+
+            let mut positions = Vec::new();
+            loop {
+                match stream.try_next() {
+                    Ok(Some(_)) => positions.push(stream.position()),
+                    Ok(None) => break,
+                    Err(_) => assert!(stream.skip_malformed()),
+                }
+            }
+            assert_eq!(7, positions.last().unwrap().byte_offset);
+            for position in positions {
+                stream.seek(position.byte_offset).unwrap();
+                assert_eq!(position, stream.position());
+            }
+        }
+
+        let (_tmpfile, stream) = mmap_file(&test_data);
+        let mut stream = stream.unwrap();
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!("a\u{fffd}", stream.by_ref().take(2).collect::<String>());
+        assert_eq!(2, stream.position().byte_offset);
+        stream.unread('\u{fffd}');
+        assert_eq!(Position { line: 1, column: 2, char_offset: 1, byte_offset: 1 }, stream.position());
+    }
+
+    #[test]
+    fn from_path_mmap_seek() {
+        let test_data = format!("{}\n世界\nabc", "あ".repeat(5000));
+        let (_tmpfile, stream) = mmap_file(test_data.as_bytes());
+        let mut stream = stream.unwrap();
+
+        let offset = test_data.find('界').unwrap();
+        stream.seek(offset).unwrap();
+        assert_eq!(Position { line: 2, column: 2, char_offset: 5002, byte_offset: offset }, stream.position());
+        assert_eq!("界", stream.read_line().unwrap());
+        assert_eq!(3, stream.position().line);
+
+        stream.seek(3).unwrap();
+        assert_eq!(Some('あ'), stream.next());
+        assert_eq!(Position { line: 1, column: 3, char_offset: 2, byte_offset: 6 }, stream.position());
+
+        // 移動した位置の行と列は、必要になったときに数える。
+        stream.seek(15000).unwrap();
+        assert_eq!(Some('\n'), stream.next());
+        stream.unread('\n');
+        stream.unread('あ');
+        assert_eq!(Position { line: 1, column: 5000, char_offset: 4999, byte_offset: 14997 }, stream.position());
+        stream.seek(offset).unwrap();
+        stream.read_line();
+        assert_eq!(Some('a'), stream.next());
+        assert_eq!(Position { line: 3, column: 2, char_offset: 5005, byte_offset: test_data.len() - 2 }, stream.position());
+        let mark = stream.mark();
+        stream.seek(0).unwrap();
+        stream.reset(&mark);
+        assert_eq!("bc", stream.to_string());
+
+        assert!(stream.seek(4).is_err());
+        assert!(stream.seek(test_data.len() + 1).is_err());
+        stream.seek(test_data.len()).unwrap();
+        assert_eq!(None, stream.next());
+
+        match CharStream::from("abc").seek(1) {
+            Err(Error::UnsupportedOperation(_)) => (),
+            _ => panic!("str can't seek"),
+        }
+    }
+
+    #[test]
+    fn from_path_mmap_reset() {
+        let (_tmpfile, stream) = mmap_file("ab\n世界\ncd".as_bytes());
+        let mut stream = stream.unwrap();
+        stream.next();
+        let mark = stream.mark();
+        assert_eq!("b", stream.read_line().unwrap());
+        assert_eq!(Some('世'), stream.next());
+
+        stream.reset(&mark);
+        assert_eq!(mark.position(), stream.position());
+        assert_eq!("b\n世界\ncd", stream.to_string());
+
+        stream.reset(&mark);
+        stream.commit(mark);
+        assert_eq!(Some('b'), stream.next());
+        assert_eq!(Some('\n'), stream.next());
+        stream.unread('\n');
+        assert_eq!(Position { line: 1, column: 3, char_offset: 2, byte_offset: 2 }, stream.position());
+    }
+
+    #[test]
+    fn from_path_mmap_splice_reset() {
+        let (_tmpfile, stream) = mmap_file(b"abc");
+        let mut stream = stream.unwrap();
+        let mark = stream.mark();
+        assert_eq!(Some('a'), stream.next());
+        stream.splice_str("x").unwrap();
+        assert_eq!(Some('x'), stream.next());
 
-        assert_eq!('H', stream.next().unwrap());
-        assert_eq!('e', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('o', stream.next().unwrap());
-        assert_eq!(' ', stream.next().unwrap());
-        assert_eq!('世', stream.next().unwrap());
-        assert_eq!('界', stream.next().unwrap());
-        assert_eq!('❤', stream.next().unwrap());
-        assert_eq!(None, stream.next());
+        stream.reset(&mark);
+        assert_eq!(Position::new(), stream.position());
+        assert_eq!("axbc", stream.to_string());
+
+        // 展開の後に作ったマークにも戻れる。
+        stream.reset(&mark);
+        stream.next();
+        let later = stream.mark();
+        assert_eq!("xb", stream.peek_n(2));
+        stream.nth(1);
+        stream.reset(&later);
+        assert_eq!("xbc", stream.to_string());
     }
 
     #[test]
-    fn from_string() {
-        let s = String::from("Hello 世界❤");
-        let mut stream = CharStream::from_string(s);
+    fn from_path_mmap_wend_iter() {
+        let test_data = "Hello\n 世界❤\n𝄞";
+        let (_tmpfile, stream) = mmap_file(test_data.as_bytes());
+        let mut stream = stream.unwrap();
+        assert_eq!(Some('H'), stream.next());
+        assert_eq!(Some('e'), stream.peek());
 
-        assert_eq!('H', stream.next().unwrap());
-        assert_eq!('e', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('o', stream.next().unwrap());
-        assert_eq!(' ', stream.next().unwrap());
-        assert_eq!('世', stream.next().unwrap());
-        assert_eq!('界', stream.next().unwrap());
-        assert_eq!('❤', stream.next().unwrap());
+        let mut iter = stream.wend_iter();
+        assert_eq!(test_data.chars().count() - 1, iter.len());
+        assert_eq!(Some('e'), iter.next());
+        assert_eq!(Some('𝄞'), iter.next_back());
+        let rest: String = iter.rev().collect();
+        let expected: String = test_data[2..test_data.len() - 4].chars().rev().collect();
+        assert_eq!(expected, rest);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_path_mmap_not_regular_file() {
+        let mut stream = CharStream::from_path_mmap("/dev/null").unwrap();
         assert_eq!(None, stream.next());
+        assert!(stream.seek(0).is_err());
+    }
+
+    fn reverse_file(test_data: &[u8], block_size: usize) -> WendIterator {
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(test_data).unwrap();
+        let mut file = internals::InternalReverseFile::with_block_size(tmpfile, 0, Vec::new(), block_size).unwrap();
+        file.count(Position::new(), LineEndings::default(), false).unwrap();
+        WendIterator::from_file(file)
     }
 
     #[test]
-    fn from_bytes() {
-        let bytes: [u8; 15] = [72, 101, 108, 108, 111, 32, 228, 184, 150, 231, 149, 140, 226, 157, 164];
-        if let Ok(mut stream) = CharStream::from_bytes(&bytes) {
-            assert_eq!('H', stream.next().unwrap());
-            assert_eq!('e', stream.next().unwrap());
-            assert_eq!('l', stream.next().unwrap());
-            assert_eq!('l', stream.next().unwrap());
-            assert_eq!('o', stream.next().unwrap());
-            assert_eq!(' ', stream.next().unwrap());
-            assert_eq!('世', stream.next().unwrap());
-            assert_eq!('界', stream.next().unwrap());
-            assert_eq!('❤', stream.next().unwrap());
-            assert_eq!(None, stream.next());
-        }else{
-            panic!("can't convert stream from bytes.");
+    fn reverse_file_blocks() {
+        let test_data = "Hello\n 世界❤\n𝄞パタトクカシーー\n";
+        let expected: String = test_data.chars().rev().collect();
+        for block_size in 8..20 {
+            let result: String = reverse_file(test_data.as_bytes(), block_size).rev().collect();
+            assert_eq!(expected, result);
+
+            let result: String = reverse_file(test_data.as_bytes(), block_size).collect();
+            assert_eq!(test_data, result);
         }
     }
 
     #[test]
-    fn from_file() {
-        let test_data = "Hello\n 世界❤";
+    fn reverse_file_meet() {
+        let test_data = "ab世界❤cd";
+        for block_size in 8..12 {
+            let mut iter = reverse_file(test_data.as_bytes(), block_size);
+            assert_eq!(7, iter.len());
+            assert_eq!(Some('d'), iter.next_back());
+            assert_eq!(Some('a'), iter.next());
+            assert_eq!(Some('c'), iter.next_back());
+            assert_eq!(4, iter.len());
+            assert_eq!(Some('❤'), iter.next_back());
+            assert_eq!(Some('b'), iter.next());
+            assert_eq!(Some('世'), iter.next());
+            assert_eq!(Some('界'), iter.next_back());
+            assert_eq!(0, iter.len());
+            assert_eq!(None, iter.next());
+            assert_eq!(None, iter.next_back());
+        }
+    }
+
+    #[test]
+    fn reverse_file_invalid_utf8() {
+        let test_data = [b'a', 0xe4, 0xb8, b'b', 0x80, 0x80, 0x80, 0x80, b'c'];
+        let expected: String = String::from_utf8_lossy(&test_data).chars().rev().collect();
+        for block_size in 8..10 {
+            let iter = reverse_file(&test_data, block_size);
+            assert_eq!(expected.chars().count(), iter.len());
+            let result: String = iter.rev().collect();
+            assert_eq!(expected, result);
+        }
+    }
+
+    #[test]
+    fn from_file_wend_iter_decode_mode() {
+        let test_data = b"ab\rc\xffd\n";
+        let open = |mode| {
+            let mut tmpfile: File = tempfile::tempfile().unwrap();
+            tmpfile.write_all(test_data).unwrap();
+            tmpfile.seek(SeekFrom::Start(0)).unwrap();
+            let mut stream = CharStream::from_file(tmpfile);
+            stream.set_buffer_size(2);
+            stream.set_decode_mode(mode);
+            stream.set_line_endings(LineEndings::AnyOf { lf: true, crlf: true, cr: true });
+            stream
+        };
+
+        let mut stream = open(DecodeMode::Strict);
+        assert_eq!(Some('a'), stream.next());
+        match stream.try_wend_iter() {
+            Err(Error::InvalidUtf8 { offset, line, ref bytes }) => assert_eq!((4, 2, vec![0xff]), (offset, line, bytes.clone())),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+        let result: String = open(DecodeMode::Lossy).wend_iter().rev().collect();
+        assert_eq!("\nd\u{fffd}c\rba", result);
+        let result: String = open(DecodeMode::Skip).wend_iter().rev().collect();
+        assert_eq!("\ndc\rba", result);
+
+        let mut iter = open(DecodeMode::Lossy).wend_iter();
+        assert_eq!((7, Some(7)), iter.size_hint());
+        assert_eq!(7, iter.len());
+        assert_eq!(Some('\n'), iter.next_back());
+        assert_eq!((6, Some(6)), iter.size_hint());
+        assert_eq!((6, Some(6)), open(DecodeMode::Skip).wend_iter().size_hint());
+    }
+
+    #[test]
+    fn from_file_wend_iter_io_error() {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        tmpfile.write_all("Hello\n 世界❤".repeat(10000).as_bytes()).unwrap();
+        let mut iter = CharStream::open(tmpfile.path()).unwrap().wend_iter();
+        assert_eq!(Some('❤'), iter.next_back());
+
+        std::fs::OpenOptions::new().write(true).open(tmpfile.path()).unwrap().set_len(4).unwrap();
+        while iter.next_back().is_some() {}
+        match iter.take_error() {
+            Some(Error::Io(ref err)) => assert_eq!(io::ErrorKind::UnexpectedEof, err.kind()),
+            _ => panic!("the truncated file can't be read."),
+        }
+        assert!(iter.take_error().is_none());
+    }
+
+    #[test]
+    fn from_file_wend_iter_after_read() {
+        let test_data = "Hello\n 世界❤\nstressed";
 
-        // write test data to tempfile
         let mut tmpfile: File = tempfile::tempfile().unwrap();
         tmpfile.write_all(test_data.as_bytes()).unwrap();
-
-        // Seek to start
         tmpfile.seek(SeekFrom::Start(0)).unwrap();
 
-        // read test data from tempfile
         let mut stream = CharStream::from_file(tmpfile);
-        assert_eq!('H', stream.next().unwrap());
-        assert_eq!('e', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('o', stream.next().unwrap());
-        assert_eq!('\n', stream.next().unwrap());
-        assert_eq!(' ', stream.next().unwrap());
-        assert_eq!('世', stream.next().unwrap());
-        assert_eq!('界', stream.next().unwrap());
-        assert_eq!('❤', stream.next().unwrap());
-        assert_eq!(None, stream.next());
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some(' '), stream.next());
+        assert_eq!(Some('\n'), stream.peek_nth(3));
+
+        let mut iter = stream.wend_iter();
+        assert_eq!(12, iter.len());
+        assert_eq!(Some('世'), iter.next());
+        let result: String = iter.rev().collect();
+        assert_eq!("desserts\n❤界", result);
     }
 
     #[test]
-    fn from_file_wend_iter() {
-        let test_data = "Hello\n 世界❤";
+    fn lines_rev_same_as_read_line() {
+        let inputs = ["", "\n", "\n\n", "a", "a\n", "a\r", "a\r\r", "a\r\n\r\n", "a\rb\r\nc", "\r\na\n\nb\n", "a\r\r\nb"];
+        for input in inputs.iter() {
+            let mut stream = CharStream::from(input);
+            let mut expected = Vec::new();
+            while let Some(line) = stream.read_line() {
+                expected.push(line);
+            }
+            expected.reverse();
+
+            let lines: Vec<String> = CharStream::from(input).lines_rev().collect();
+            assert_eq!(expected, lines, "input: {:?}", input);
+        }
+    }
+
+    const LINE_ENDINGS: [LineEndings; 6] = [
+        LineEndings::Lf,
+        LineEndings::CrLf,
+        LineEndings::AnyOf { lf: true, crlf: true, cr: false },
+        LineEndings::AnyOf { lf: true, crlf: false, cr: true },
+        LineEndings::AnyOf { lf: false, crlf: true, cr: true },
+        LineEndings::Unicode,
+    ];
+
+    #[test]
+    fn line_endings_lines_rev_same_as_read_line() {
+        let inputs = ["", "\n", "\r", "\r\r\n", "a\r", "a\rb\r\nc\n", "\n\ra\r\n\r\n", "a\u{2028}b\u{85}\u{0c}", "a\u{0b}\r\n"];
+        for &endings in LINE_ENDINGS.iter() {
+            for input in inputs.iter() {
+                let mut stream = CharStream::from(input);
+                stream.set_line_endings(endings);
+                let mut expected: Vec<String> = stream.lines().collect();
+                expected.reverse();
+
+                let mut stream = CharStream::from(input);
+                stream.set_line_endings(endings);
+                let lines: Vec<String> = stream.lines_rev().collect();
+                assert_eq!(expected, lines, "input: {:?}, endings: {:?}", input, endings);
+            }
+        }
+    }
+
+    #[test]
+    fn line_endings_read_line() {
+        let input = "a\rb\r\nc\nd\u{2028}e";
+        let expected: [&[&str]; 6] = [
+            &["a\rb\r", "c", "d\u{2028}e"],
+            &["a\rb", "c\nd\u{2028}e"],
+            &["a\rb", "c", "d\u{2028}e"],
+            &["a", "b", "", "c", "d\u{2028}e"],
+            &["a", "b", "c\nd\u{2028}e"],
+            &["a", "b", "c", "d", "e"],
+        ];
+        for (&endings, &expected) in LINE_ENDINGS.iter().zip(expected.iter()) {
+            let mut stream = CharStream::from(input);
+            stream.set_line_endings(endings);
+            assert_eq!(endings, stream.line_endings());
+            let lines: Vec<String> = stream.lines().collect();
+            assert_eq!(expected, &lines[..], "endings: {:?}", endings);
+        }
+    }
+
+    #[test]
+    fn line_endings_position() {
+        // 行の数は read_line と同じになる。
+        let input = "a\rb\r\nc\nd\u{2028}e\r";
+        for &endings in LINE_ENDINGS.iter() {
+            let mut stream = CharStream::from(input);
+            stream.set_line_endings(endings);
+            let mut lines = 0;
+            while stream.read_line().is_some() {
+                lines += 1;
+                if stream.peek().is_some() {
+                    assert_eq!(lines + 1, stream.position().line, "endings: {:?}", endings);
+                    assert_eq!(1, stream.position().column, "endings: {:?}", endings);
+                }
+            }
+        }
+
+        let mut stream = CharStream::from("ab\r\ncd");
+        stream.set_line_endings(LineEndings::Unicode);
+        stream.nth(2);
+        assert_eq!(Position { line: 2, column: 1, char_offset: 3, byte_offset: 3 }, stream.position());
+        assert_eq!(Some('\n'), stream.next());
+        assert_eq!(Position { line: 2, column: 1, char_offset: 4, byte_offset: 4 }, stream.position());
+        stream.unread('\n');
+        stream.unread('\r');
+        assert_eq!(Position { line: 1, column: 3, char_offset: 2, byte_offset: 2 }, stream.position());
+        stream.nth(1);
+        stream.unread('\n');
+        assert_eq!(Some('\n'), stream.next());
+        assert_eq!(Position { line: 2, column: 1, char_offset: 4, byte_offset: 4 }, stream.position());
+    }
+
+    #[test]
+    fn line_endings_splice_and_seek() {
+        let mut stream = CharStream::from("a\rb");
+        stream.set_line_endings(LineEndings::AnyOf { lf: true, crlf: true, cr: true });
+        assert_eq!(Some('a'), stream.next());
+        stream.splice_str("x\ry\r").unwrap();
+        let lines: Vec<String> = stream.lines().collect();
+        assert_eq!(vec!["x", "y", "", "b"], lines);
+        assert_eq!(Some(Position { line: 2, column: 2, char_offset: 3, byte_offset: 3 }), stream.source_position());
+
+        let test_data = format!("{}\r世界\r\n", "a\r".repeat(5000));
+        let (_tmpfile, stream) = mmap_file(test_data.as_bytes());
+        let mut stream = stream.unwrap();
+        stream.set_line_endings(LineEndings::Unicode);
+        let offset = test_data.find('界').unwrap();
+        stream.seek(offset).unwrap();
+        assert_eq!(Position { line: 5002, column: 2, char_offset: 10002, byte_offset: offset }, stream.position());
+        stream.seek(offset + 4).unwrap();
+        assert_eq!(Some('\n'), stream.next());
+        assert_eq!(Position { line: 5003, column: 1, char_offset: 10005, byte_offset: offset + 5 }, stream.position());
+    }
+
+    #[test]
+    fn from_file_tail() {
+        let mut test_data = String::new();
+        for i in 0..10000 {
+            test_data.push_str(&format!("{}: 世界❤\r\n", i));
+        }
 
-        // write test data to tempfile
         let mut tmpfile: File = tempfile::tempfile().unwrap();
         tmpfile.write_all(test_data.as_bytes()).unwrap();
-
-        // Seek to start
         tmpfile.seek(SeekFrom::Start(0)).unwrap();
 
-        // read test data from tempfile
-        let file_stream = CharStream::from_file(tmpfile);
-        let mut stream = file_stream.wend_iter().rev();
+        let stream = CharStream::from_file(tmpfile);
+        assert_eq!(vec!["9997: 世界❤", "9998: 世界❤", "9999: 世界❤"], stream.tail(3));
+    }
 
-        assert_eq!('❤', stream.next().unwrap());
-        assert_eq!('界', stream.next().unwrap());
-        assert_eq!('世', stream.next().unwrap());
-        assert_eq!(' ', stream.next().unwrap());
-        assert_eq!('\n', stream.next().unwrap());
-        assert_eq!('o', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('l', stream.next().unwrap());
-        assert_eq!('e', stream.next().unwrap());
-        assert_eq!('H', stream.next().unwrap());
+    #[test]
+    fn follow_appended_and_truncated() {
+        use std::fs::OpenOptions;
+        use std::thread;
+        use std::time::Duration;
+
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        // 文字の途中までを書いておき、残りを後から追記する。
+        let mut bytes = "Hello\n世".as_bytes().to_vec();
+        bytes.extend_from_slice(&"界\n".as_bytes()[..2]);
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+
+        let mut stream = CharStream::follow(&path).unwrap();
+        let handle = stream.follow_handle().unwrap();
+        handle.set_poll_interval(Duration::from_millis(1));
+        // 期限を過ぎたら止めて、待ち続けずに失敗させる。
+        let deadline = handle.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(10));
+            deadline.stop();
+        });
+
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(Some('世'), stream.next());
+
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            let mut file = OpenOptions::new().append(true).open(&writer_path).unwrap();
+            file.write_all(&"界\n".as_bytes()[2..]).unwrap();
+        });
+        assert_eq!("界", stream.read_line().unwrap());
+        writer.join().unwrap();
+
+        let writer = thread::spawn(move || {
+            File::create(&path).unwrap().write_all(b"xy\n").unwrap();
+        });
+        assert_eq!("xy", stream.read_line().unwrap());
+        writer.join().unwrap();
+
+        assert!(!handle.is_stopped());
+        handle.stop();
         assert_eq!(None, stream.next());
+        drop(tmpfile);
     }
 
     #[test]
-    fn read_line() {
-        let test_data = "Hello\n 世界❤";
-        let mut stream = CharStream::from(test_data);
-        assert_eq!("Hello", stream.read_line().unwrap());
-        assert_eq!(" 世界❤", stream.read_line().unwrap());
+    fn follow_buffer_size() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        File::create(tmpfile.path()).unwrap().write_all("世界❤\n".repeat(100).as_bytes()).unwrap();
+
+        let mut stream = CharStream::follow(tmpfile.path()).unwrap();
+        stream.set_buffer_size(2);
+        for _ in 0..100 {
+            assert_eq!("世界❤", stream.read_line().unwrap());
+        }
+        stream.follow_handle().unwrap().stop();
         assert_eq!(None, stream.next());
     }
 
     #[test]
-    fn from_file_read_line() {
-        let test_data = "Hello\n 世界❤";
+    fn follow_wend_iter() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let stream = CharStream::follow(tmpfile.path()).unwrap();
+        match stream.try_wend_iter() {
+            Err(Error::UnsupportedOperation(_)) => (),
+            _ => panic!("follow can't be converted"),
+        }
+        let stream = CharStream::follow(tmpfile.path()).unwrap();
+        match stream.try_tail(1) {
+            Err(Error::UnsupportedOperation(_)) => (),
+            _ => panic!("follow can't be read from the end"),
+        }
+        assert!(CharStream::from("").follow_handle().is_none());
+    }
 
-        // write test data to tempfile
-        let mut tmpfile: File = tempfile::tempfile().unwrap();
-        tmpfile.write_all(test_data.as_bytes()).unwrap();
+    // 1 バイトずつ読み込むリーダー
+    struct OneByteReader {
+        bytes: Vec<u8>,
+        index: usize,
+    }
 
-        // Seek to start
+    impl OneByteReader {
+        fn new(bytes: &[u8]) -> io::BufReader<OneByteReader> {
+            io::BufReader::with_capacity(1, OneByteReader { bytes: bytes.to_vec(), index: 0 })
+        }
+    }
+
+    impl Read for OneByteReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.index >= self.bytes.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.bytes[self.index];
+            self.index += 1;
+            Ok(1)
+        }
+    }
+
+    fn encode_utf16(s: &str, big_endian: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for unit in s.encode_utf16() {
+            if big_endian {
+                bytes.extend_from_slice(&unit.to_be_bytes());
+            }else{
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn from_reader_with_encoding() {
+        let text = "Hello\r\n 世界❤\n😀𝄞";
+
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(encode_utf16(text, false));
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf8);
+        assert_eq!("Hello", stream.read_line().unwrap());
+        assert_eq!(text[7..], stream.to_string());
+
+        let bytes = encode_utf16(text, true);
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf16Be);
+        assert_eq!(text, stream.to_string());
+
+        let mut bytes = vec![0x00, 0x00, 0xfe, 0xff];
+        for c in text.chars() {
+            bytes.extend_from_slice(&(c as u32).to_be_bytes());
+        }
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf16Le);
+        assert_eq!(text, stream.to_string());
+
+        let mut bytes = vec![0xef, 0xbb, 0xbf];
+        bytes.extend_from_slice(text.as_bytes());
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf32Le);
+        assert_eq!(text, stream.to_string());
+
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&[0xff, 0xfe]), Encoding::Utf8);
+        assert_eq!(None, stream.try_next().unwrap());
+    }
+
+    #[test]
+    fn unpaired_surrogates() {
+        let bytes = [0x61, 0x00, 0x3d, 0xd8, 0x62, 0x00];
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf16Le);
+        assert_eq!(Some('a'), stream.try_next().unwrap());
+        match stream.try_next() {
+            Err(Error::Malformed { encoding, offset, .. }) => assert_eq!((Encoding::Utf16Le, 2), (encoding, offset)),
+            _ => panic!("unpaired surrogate must be an error."),
+        }
+
+        let bytes = [0xfe, 0xff, 0x00, 0x61, 0xde, 0x00];
+        match CharStream::from_bytes_with_encoding(&bytes, Encoding::Utf8) {
+            Err(Error::Malformed { encoding, offset, .. }) => assert_eq!((Encoding::Utf16Be, 4), (encoding, offset)),
+            _ => panic!("unpaired surrogate must be an error."),
+        }
+
+        let bytes = [0x00, 0xd8, 0x00, 0x00, 0x00, 0x00];
+        match CharStream::from_bytes_with_encoding(&bytes, Encoding::Utf32Le) {
+            Err(Error::Malformed { encoding, offset, .. }) => assert_eq!((Encoding::Utf32Le, 0), (encoding, offset)),
+            _ => panic!("surrogate must be an error."),
+        }
+    }
+
+    #[test]
+    fn from_reader_invalid_utf8_split() {
+        let mut bytes = "世界".as_bytes().to_vec();
+        bytes.extend_from_slice(&[0xe2, 0x9d, 0x41]);
+        let mut stream = CharStream::from_reader(OneByteReader::new(&bytes));
+        assert_eq!(Some('世'), stream.try_next().unwrap());
+        assert_eq!(Some('界'), stream.try_next().unwrap());
+        match stream.try_next() {
+            Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(6, offset),
+            _ => panic!("invalid utf8 must be an error."),
+        }
+
+        let mut stream = CharStream::from_reader(OneByteReader::new(&[0x41, 0xf0, 0x9f, 0x98]));
+        assert_eq!(Some('A'), stream.try_next().unwrap());
+        match stream.try_next() {
+            Err(Error::InvalidUtf8 { offset, .. }) => assert_eq!(1, offset),
+            _ => panic!("truncated utf8 must be an error."),
+        }
+    }
+
+    #[test]
+    fn japanese_encodings() {
+        let shift_jis = [0x90, 0xa2, 0x8a, 0x45, 0x0a, 0x83, 0x70, 0x83, 0x5e, 0x83, 0x67, 0x83, 0x4e, 0x83, 0x4a, 0x83, 0x56, 0x81, 0x5b, 0x81, 0x5b, 0x0a];
+        let euc_jp = [0xc0, 0xa4, 0xb3, 0xa6, 0x0a, 0xa5, 0xd1, 0xa5, 0xbf, 0xa5, 0xc8, 0xa5, 0xaf, 0xa5, 0xab, 0xa5, 0xb7, 0xa1, 0xbc, 0xa1, 0xbc, 0x0a];
+        let iso_2022_jp = [0x1b, 0x24, 0x42, 0x40, 0x24, 0x33, 0x26, 0x1b, 0x28, 0x42, 0x0a, 0x1b, 0x24, 0x42, 0x25, 0x51, 0x25, 0x3f, 0x25, 0x48, 0x25, 0x2f, 0x25, 0x2b, 0x25, 0x37, 0x21, 0x3c, 0x21, 0x3c, 0x1b, 0x28, 0x42, 0x0a];
+
+        for &(bytes, encoding) in [(&shift_jis[..], Encoding::ShiftJis), (&euc_jp[..], Encoding::EucJp), (&iso_2022_jp[..], Encoding::Iso2022Jp)].iter() {
+            let mut tmpfile: File = tempfile::tempfile().unwrap();
+            tmpfile.write_all(bytes).unwrap();
+            tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+            let mut stream = CharStream::from_file_with_encoding(tmpfile, encoding);
+            assert_eq!("世界", stream.read_line().unwrap());
+            assert_eq!("パタトクカシーー", stream.read_line().unwrap());
+            assert_eq!(None, stream.try_next().unwrap());
+
+            let mut stream = CharStream::from_bytes_with_encoding(bytes, encoding).unwrap();
+            assert_eq!("世界\nパタトクカシーー\n", stream.to_string());
+
+            let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(bytes), encoding);
+            assert_eq!("世界\nパタトクカシーー\n", stream.to_string());
+        }
+    }
+
+    #[test]
+    fn iso_2022_jp_state_across_lines() {
+        // ESC ( J で切り替えた JIS X 0201 Roman は、改行をまたいでも続く。
+        let bytes = [0x1b, 0x28, 0x4a, 0x5c, 0x0a, 0x5c, 0x7e, 0x1b, 0x28, 0x42, 0x5c];
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Iso2022Jp);
+        assert_eq!("¥", stream.read_line().unwrap());
+        assert_eq!("¥‾\\", stream.to_string());
+    }
+
+    #[test]
+    fn malformed_shift_jis() {
+        let bytes = [0x61, 0x90, 0xa2, 0x0a, 0x81, 0x20];
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::ShiftJis);
+        assert_eq!("a世", stream.read_line().unwrap());
+        match stream.try_next() {
+            Err(Error::Malformed { encoding, offset, .. }) => assert_eq!((Encoding::ShiftJis, 4), (encoding, offset)),
+            _ => panic!("malformed Shift_JIS must be an error."),
+        }
+
+        match CharStream::from_bytes_with_encoding(&bytes, Encoding::ShiftJis) {
+            Err(Error::Malformed { encoding, offset, .. }) => assert_eq!((Encoding::ShiftJis, 4), (encoding, offset)),
+            _ => panic!("malformed Shift_JIS must be an error."),
+        }
+    }
+
+    #[test]
+    fn single_byte_encodings() {
+        let bytes = [0x80, 0x93, 0x41, 0x94, 0x9f, 0x0a, 0xe9, 0xff];
+        let mut stream = CharStream::from_bytes_with_encoding(&bytes, Encoding::Windows1252).unwrap();
+        assert_eq!("€“A”Ÿ\néÿ", stream.to_string());
+        let mut stream = CharStream::from_bytes_with_encoding(&bytes, Encoding::Latin1).unwrap();
+        assert_eq!("\u{80}\u{93}A\u{94}\u{9f}\néÿ", stream.to_string());
+        let mut stream = CharStream::from_bytes_with_encoding(&bytes, Encoding::Iso8859_15).unwrap();
+        assert_eq!("\u{80}\u{93}A\u{94}\u{9f}\néÿ", stream.to_string());
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&[0xf0, 0xd2, 0xc9, 0xd7, 0xc5, 0xd4, 0x0a, 0xa3]).unwrap();
         tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        let mut stream = CharStream::from_file_with_encoding(tmpfile, Encoding::Koi8R);
+        assert_eq!("Привет", stream.read_line().unwrap());
+        assert_eq!("ё", stream.read_line().unwrap());
 
-        // read test data from tempfile
+        let bytes = [0xaf, 0xf3, 0xb3, 0xe6];
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Iso8859_2);
+        assert_eq!("Żółć", stream.to_string());
+    }
+
+    #[test]
+    fn undefined_single_byte() {
+        // 符号表で未定義のバイトは、WHATWG の定義どおり同じ値の C1 制御文字になる。
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&[0x41, 0x0a, 0x42, 0x81]), Encoding::Windows1252);
+        assert_eq!("A", stream.read_line().unwrap());
+        assert_eq!(Some('B'), stream.try_next().unwrap());
+        assert_eq!(Some('\u{81}'), stream.try_next().unwrap());
+        assert_eq!(None, stream.try_next().unwrap());
+        let mut stream = CharStream::from_bytes_with_encoding(&[0x98, 0x8a], Encoding::Windows1250).unwrap();
+        assert_eq!("\u{98}Š", stream.to_string());
+
+        let mut stream = CharStream::from_bytes_with_encoding(&[0x81], Encoding::Latin1).unwrap();
+        assert_eq!("\u{81}", stream.to_string());
+    }
+
+    #[test]
+    fn detect_encoding() {
+        let detection = Encoding::detect(b"Hello");
+        assert_eq!((Encoding::Utf8, 1.0), (detection.encoding, detection.confidence));
+        let detection = Encoding::detect(&[0xfe, 0xff, 0x4e, 0x16]);
+        assert_eq!((Encoding::Utf16Be, 1.0), (detection.encoding, detection.confidence));
+
+        let detection = Encoding::detect("Hello 世界❤".as_bytes());
+        assert_eq!(Encoding::Utf8, detection.encoding);
+        assert!(detection.confidence > 0.8 && detection.confidence < 1.0);
+
+        assert_eq!(Encoding::Utf16Le, Encoding::detect(&encode_utf16("Hello 世界", false)).encoding);
+        assert_eq!(Encoding::Utf16Be, Encoding::detect(&encode_utf16("Hello 世界", true)).encoding);
+        assert_eq!(Encoding::Iso2022Jp, Encoding::detect(&[0x1b, 0x24, 0x42, 0x40, 0x24, 0x33, 0x26, 0x1b, 0x28, 0x42]).encoding);
+
+        let shift_jis = [0x90, 0xa2, 0x8a, 0x45, 0x0a, 0x83, 0x70, 0x83, 0x5e, 0x83, 0x67, 0x83, 0x4e, 0x83, 0x4a, 0x83, 0x56, 0x81, 0x5b, 0x81, 0x5b, 0x0a];
+        let detection = Encoding::detect(&shift_jis);
+        assert_eq!(Encoding::ShiftJis, detection.encoding);
+        assert!(detection.confidence > 0.5);
+
+        let euc_jp = [0xc0, 0xa4, 0xb3, 0xa6, 0x0a, 0xa5, 0xd1, 0xa5, 0xbf, 0xa5, 0xc8, 0xa5, 0xaf, 0xa5, 0xab, 0xa5, 0xb7, 0xa1, 0xbc, 0xa1, 0xbc, 0x0a];
+        let detection = Encoding::detect(&euc_jp);
+        assert_eq!(Encoding::EucJp, detection.encoding);
+        assert!(detection.confidence > 0.5);
+
+        let windows_1252 = [0x63, 0x61, 0x66, 0xe9, 0x20, 0x93, 0x6e, 0x61, 0xef, 0x76, 0x65, 0x94];
+        let detection = Encoding::detect(&windows_1252);
+        assert_eq!(Encoding::Windows1252, detection.encoding);
+        assert!(detection.confidence < 0.5);
+        let (mut stream, _) = CharStream::from_bytes_detect(&windows_1252).unwrap();
+        assert_eq!("café “naïve”", stream.to_string());
+    }
+
+    #[test]
+    fn detect_encoding_prefix() {
+        // 調べる範囲の境目で切れた UTF-8 の文字は、不正とみなさない。
+        let mut text = "a".repeat(DETECT_SIZE - 1);
+        text.push_str("世界");
+        assert_eq!(Encoding::Utf8, Encoding::detect(text.as_bytes()).encoding);
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&[0x0a, 0xa4, 0xb3, 0xa4, 0xf3, 0xa4, 0xcb, 0xa4, 0xc1, 0xa4, 0xcf]).unwrap();
+        tmpfile.seek(SeekFrom::Start(1)).unwrap();
+        let (mut stream, detection) = CharStream::from_file_detect(tmpfile).unwrap();
+        assert_eq!(Encoding::EucJp, detection.encoding);
+        assert_eq!("こんにちは", stream.to_string());
+    }
+
+    #[test]
+    fn lossy_utf8() {
+        let bytes = [0x61, 0xf1, 0x80, 0x80, 0xe1, 0x80, 0xc2, 0x62, 0x80, 0x63, 0x80, 0xbf, 0x64, 0xed, 0xa0, 0x80, 0x0a, 0xf0, 0x9f, 0x98];
+        let expected = "a\u{fffd}\u{fffd}\u{fffd}b\u{fffd}c\u{fffd}\u{fffd}d\u{fffd}\u{fffd}\u{fffd}\n\u{fffd}";
+        assert_eq!(String::from_utf8_lossy(&bytes), expected);
+
+        let mut stream = CharStream::from_reader(OneByteReader::new(&bytes));
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!(expected, stream.to_string());
+        assert_eq!(10, stream.replacements());
+
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&bytes).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
         let mut stream = CharStream::from_file(tmpfile);
-        assert_eq!("Hello", stream.read_line().unwrap());
-        assert_eq!(" 世界❤", stream.read_line().unwrap());
-        assert_eq!(None, stream.next());
+        stream.set_decode_mode(DecodeMode::Skip);
+        assert_eq!("abcd", stream.read_line().unwrap());
+        assert_eq!(None, stream.try_next().unwrap());
+        assert_eq!(10, stream.replacements());
+
+        let mut stream = CharStream::from_bytes_with_mode(&bytes, Encoding::Utf8, DecodeMode::Lossy).unwrap();
+        assert_eq!(expected, stream.to_string());
+        assert_eq!(10, stream.replacements());
+        assert!(CharStream::from_bytes_with_mode(&bytes, Encoding::Utf8, DecodeMode::Strict).is_err());
+        assert_eq!(0, CharStream::from("abc").replacements());
+    }
+
+    #[test]
+    fn lossy_other_encodings() {
+        // 対になっていない上位サロゲートの後ろの 'b' は、そのまま読める。
+        let bytes = [0x61, 0x00, 0x3d, 0xd8, 0x62, 0x00, 0x00, 0xdc, 0x3d, 0xd8];
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::Utf16Le);
+        stream.set_decode_mode(DecodeMode::Lossy);
+        assert_eq!("a\u{fffd}b\u{fffd}\u{fffd}", stream.to_string());
+        assert_eq!(3, stream.replacements());
+
+        let bytes = [0x61, 0x90, 0xa2, 0x81, 0x20, 0x0a, 0x82];
+        let mut stream = CharStream::from_bytes_with_mode(&bytes, Encoding::ShiftJis, DecodeMode::Lossy).unwrap();
+        assert_eq!("a世\u{fffd} \n\u{fffd}", stream.to_string());
+        let mut stream = CharStream::from_reader_with_encoding(OneByteReader::new(&bytes), Encoding::ShiftJis);
+        stream.set_decode_mode(DecodeMode::Skip);
+        assert_eq!("a世 \n", stream.to_string());
+        assert_eq!(2, stream.replacements());
+    }
+
+    #[test]
+    fn from_file_with_encoding_wend_iter() {
+        let mut tmpfile: File = tempfile::tempfile().unwrap();
+        tmpfile.write_all(&encode_utf16("\u{feff}stressed", true)).unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+
+        let stream = CharStream::from_file_with_encoding(tmpfile, Encoding::Utf16Le);
+        let result: String = stream.wend_iter().rev().collect();
+        assert_eq!("desserts", result);
     }
 }
@@ -0,0 +1,123 @@
+///
+/// Which chars end a line, used by `CharStream::read_line`, `lines`, `lines_rev` and `position`.
+///
+/// The default is `AnyOf { lf: true, crlf: true, cr: false }`.
+/// When `crlf` is on, a '\r' at the end of the input also ends the line.
+///
+/// Example:
+///
+/// ```
+/// use char_stream::{CharStream, LineEndings};
+///
+/// let mut stream = CharStream::from("classic\rmac\rfile");
+/// stream.set_line_endings(LineEndings::AnyOf { lf: true, crlf: true, cr: true });
+///
+/// assert_eq!("classic", stream.read_line().unwrap());
+/// assert_eq!(2, stream.position().line);
+/// let lines: Vec<String> = stream.lines().collect();
+/// assert_eq!(vec!["mac", "file"], lines);
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LineEndings {
+    ///
+    /// only "\n".
+    ///
+    Lf,
+
+    ///
+    /// only "\r\n".
+    ///
+    CrLf,
+
+    ///
+    /// any of "\n", "\r\n" and "\r" which are turned on.
+    ///
+    AnyOf { lf: bool, crlf: bool, cr: bool },
+
+    ///
+    /// "\n", "\r\n", "\r", VT (U+000B), FF (U+000C), NEL (U+0085), LS (U+2028) and PS (U+2029).
+    ///
+    Unicode,
+}
+
+impl Default for LineEndings {
+    fn default() -> LineEndings {
+        LineEndings::AnyOf { lf: true, crlf: true, cr: false }
+    }
+}
+
+///
+/// what a char does to the line of the position.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineBreak {
+    No,
+    Yes,
+    // "\r\n" の '\n' で、'\r' で既に改行している。
+    Continued,
+}
+
+impl LineEndings {
+    pub(crate) fn lf(self) -> bool {
+        match self {
+            LineEndings::Lf | LineEndings::Unicode => true,
+            LineEndings::CrLf => false,
+            LineEndings::AnyOf { lf, .. } => lf,
+        }
+    }
+
+    pub(crate) fn crlf(self) -> bool {
+        match self {
+            LineEndings::CrLf | LineEndings::Unicode => true,
+            LineEndings::Lf => false,
+            LineEndings::AnyOf { crlf, .. } => crlf,
+        }
+    }
+
+    pub(crate) fn cr(self) -> bool {
+        match self {
+            LineEndings::Unicode => true,
+            LineEndings::Lf | LineEndings::CrLf => false,
+            LineEndings::AnyOf { cr, .. } => cr,
+        }
+    }
+
+    ///
+    /// whether the char ends a line by itself, other than '\n' and '\r'.
+    ///
+    pub(crate) fn is_other_break(self, c: char) -> bool {
+        self == LineEndings::Unicode && matches!(c, '\u{0b}' | '\u{0c}' | '\u{85}' | '\u{2028}' | '\u{2029}')
+    }
+
+    ///
+    /// whether the last char of the input ends the line.
+    ///
+    pub(crate) fn ends_last_line(self, c: char) -> bool {
+        match c {
+            '\n' => self.lf(),
+            '\r' => self.cr() || self.crlf(),
+            c => self.is_other_break(c),
+        }
+    }
+
+    ///
+    /// how the char moves the position to the next line.
+    ///  `after_cr` is whether the previous char is '\r'.
+    ///
+    pub(crate) fn line_break(self, c: char, after_cr: bool) -> LineBreak {
+        match c {
+            '\r' if self.cr() => LineBreak::Yes,
+            '\n' if after_cr && self.crlf() => {
+                if self.cr() {
+                    LineBreak::Continued
+                }else{
+                    LineBreak::Yes
+                }
+            },
+            '\n' if self.lf() => LineBreak::Yes,
+            c if self.is_other_break(c) => LineBreak::Yes,
+            _ => LineBreak::No,
+        }
+    }
+}
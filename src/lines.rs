@@ -0,0 +1,28 @@
+use std::iter::Iterator;
+use CharStream;
+
+///
+/// Iterator over lines of CharStream, made by `CharStream::lines`.
+///
+/// Lines are split by `CharStream::read_line`.
+///
+#[derive(Debug)]
+pub struct Lines<'s, 'a: 's> {
+    stream: &'s mut CharStream<'a>,
+}
+
+impl<'s, 'a> Lines<'s, 'a> {
+    pub(crate) fn new(stream: &'s mut CharStream<'a>) -> Lines<'s, 'a> {
+        Lines {
+            stream
+        }
+    }
+}
+
+impl<'s, 'a> Iterator for Lines<'s, 'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.stream.read_line()
+    }
+}
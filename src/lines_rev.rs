@@ -0,0 +1,102 @@
+use std::iter::Iterator;
+use {LineEndings, WendIterator};
+
+///
+/// Iterator over lines from the end of CharStream, made by `CharStream::lines_rev`.
+///
+/// Lines are split in the same way as `CharStream::read_line`, by `LineEndings` of the stream.
+///
+#[derive(Debug)]
+pub struct LinesRev {
+    chars: WendIterator,
+    endings: LineEndings,
+    // 先読みして戻した文字。後ろのものから順に積む。
+    held: Vec<char>,
+    started: bool,
+    pending: bool,
+}
+
+impl LinesRev {
+    pub(crate) fn new(chars: WendIterator, endings: LineEndings) -> LinesRev {
+        LinesRev {
+            chars,
+            endings,
+            held: Vec::new(),
+            started: false,
+            pending: true,
+        }
+    }
+
+    fn next_back_char(&mut self) -> Option<char> {
+        match self.held.pop() {
+            Some(c) => Some(c),
+            None => self.chars.next_back(),
+        }
+    }
+
+    fn peek_back_char(&mut self) -> Option<char> {
+        if self.held.is_empty() {
+            let c = self.chars.next_back()?;
+            self.held.push(c);
+        }
+        self.held.last().cloned()
+    }
+
+    // 後ろから読んだ文字が改行なら、"\r\n" の '\r' も読んで true を返す。
+    fn is_line_end(&mut self, c: char) -> bool {
+        match c {
+            '\n' => {
+                if self.endings.crlf() && self.peek_back_char() == Some('\r') {
+                    self.held.pop();
+                    return true;
+                }
+                self.endings.lf()
+            },
+            '\r' => self.endings.cr(),
+            c => self.endings.is_other_break(c),
+        }
+    }
+
+    // 末尾の改行は空行にならない。
+    fn skip_last_line_end(&mut self) -> bool {
+        match self.next_back_char() {
+            None => false,
+            Some('\r') if self.endings.ends_last_line('\r') => true,
+            Some(c) => {
+                if !self.is_line_end(c) {
+                    self.held.push(c);
+                }
+                true
+            },
+        }
+    }
+}
+
+impl Iterator for LinesRev {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if !self.pending {
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            if !self.skip_last_line_end() {
+                self.pending = false;
+                return None;
+            }
+        }
+
+        let mut chars = Vec::new();
+        self.pending = false;
+        while let Some(c) = self.next_back_char() {
+            if self.is_line_end(c) {
+                self.pending = true;
+                break;
+            }
+            chars.push(c);
+        }
+
+        Some(chars.into_iter().rev().collect())
+    }
+}
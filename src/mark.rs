@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use Position;
+
+// minimum size of the retention buffer before released marks are swept.
+const MIN_RELEASE_AT: usize = 1024;
+
+///
+/// A checkpoint of CharStream made by `CharStream::mark`.
+///
+/// A stream which can move back by itself, like one made by `CharStream::from`,
+/// remembers where the mark was made in its input.
+/// Other streams keep every char read since their oldest outstanding mark,
+/// so they can be rewound by `CharStream::reset`.
+/// The chars are released when the mark is dropped or committed.
+///
+#[derive(Debug, Clone)]
+pub struct Mark {
+    checkpoint: Arc<Checkpoint>,
+}
+
+impl Mark {
+    ///
+    /// position of the stream when the mark was made.
+    ///
+    pub fn position(&self) -> Position {
+        self.checkpoint.position
+    }
+
+    ///
+    /// offset in the input of the stream, and chars pushed back or peeked at the mark.
+    ///  None if the stream can't move back by itself.
+    ///
+    pub(crate) fn resume(&self) -> Option<(usize, &[char])> {
+        self.checkpoint.offset.map(|offset| (offset, &self.checkpoint.buffer[..]))
+    }
+
+    ///
+    /// whether the char before the mark is '\r'.
+    ///
+    pub(crate) fn after_cr(&self) -> bool {
+        self.checkpoint.after_cr
+    }
+}
+
+#[derive(Debug)]
+struct Checkpoint {
+    position: Position,
+    after_cr: bool,
+    offset: Option<usize>,
+    buffer: Vec<char>,
+    live: Arc<AtomicUsize>,
+}
+
+impl Drop for Checkpoint {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+///
+/// chars read since the oldest outstanding mark.
+///
+#[derive(Debug)]
+pub(crate) struct Retention {
+    chars: VecDeque<char>,
+    start: usize,
+    marks: Vec<Weak<Checkpoint>>,
+    live: Arc<AtomicUsize>,
+    release_at: usize,
+    seekable: bool,
+    keeps_chars: bool,
+    // 最後に読んだ文字。戻された文字が入力と同じかを調べる。
+    last: Option<char>,
+}
+
+impl Retention {
+    ///
+    /// `seekable` is true if the stream can move back to a mark by itself.
+    ///
+    pub fn new(seekable: bool) -> Retention {
+        Retention {
+            chars: VecDeque::new(),
+            start: 0,
+            marks: Vec::new(),
+            live: Arc::new(AtomicUsize::new(0)),
+            release_at: MIN_RELEASE_AT,
+            seekable,
+            keeps_chars: !seekable,
+            last: None,
+        }
+    }
+
+    ///
+    /// whether the stream can move back to a mark by itself.
+    ///
+    pub fn set_seekable(&mut self, seekable: bool) {
+        self.seekable = seekable;
+        self.keeps_chars |= !seekable;
+    }
+
+    pub fn keeps_chars(&self) -> bool {
+        self.keeps_chars
+    }
+
+    ///
+    /// keep chars read from `char_offset` until the marks are released,
+    ///  because the stream can't move back to the marks by itself, or doesn't read the same chars as its input after them.
+    ///
+    pub fn keep_chars(&mut self, char_offset: usize) {
+        self.chars.clear();
+        self.start = char_offset;
+        self.keeps_chars = true;
+    }
+
+    ///
+    /// whether pushing back `c` makes the stream read chars which are not in its input,
+    ///  so it can't move back to the marks by itself anymore.
+    ///
+    pub fn is_foreign(&self, c: char) -> bool {
+        self.live.load(Ordering::SeqCst) > 0 && !self.keeps_chars && self.last != Some(c)
+    }
+
+    ///
+    /// the oldest outstanding mark.
+    ///
+    pub fn oldest(&self) -> Option<Mark> {
+        self.marks.iter()
+            .filter_map(|mark| mark.upgrade())
+            .min_by_key(|checkpoint| checkpoint.position.char_offset)
+            .map(|checkpoint| Mark { checkpoint })
+    }
+
+    ///
+    /// `offset` is the offset in the input of the stream if it can move back by itself,
+    ///  and `buffer` are chars which are pushed back or peeked but not read yet.
+    ///
+    pub fn mark(&mut self, position: Position, after_cr: bool, offset: Option<usize>, buffer: Vec<char>) -> Mark {
+        if self.live.load(Ordering::SeqCst) == 0 {
+            self.clear(position.char_offset);
+        }
+        self.live.fetch_add(1, Ordering::SeqCst);
+
+        let checkpoint = Arc::new(Checkpoint {
+            position,
+            after_cr,
+            offset,
+            buffer,
+            live: self.live.clone(),
+        });
+        self.marks.push(Arc::downgrade(&checkpoint));
+
+        Mark { checkpoint }
+    }
+
+    pub fn owns(&self, mark: &Mark) -> bool {
+        Arc::ptr_eq(&self.live, &mark.checkpoint.live)
+    }
+
+    ///
+    /// keep a char read at `char_offset` if any mark is outstanding.
+    ///
+    pub fn push(&mut self, c: char, char_offset: usize) {
+        self.last = Some(c);
+        if self.live.load(Ordering::SeqCst) == 0 {
+            if !self.marks.is_empty() {
+                self.clear(char_offset);
+            }
+            return;
+        }
+
+        if !self.keeps_chars {
+            // 文字は覚えないが、捨てられたマークは掃除する。
+            if self.marks.len() >= self.release_at {
+                self.release();
+            }
+            return;
+        }
+
+        self.chars.push_back(c);
+        if self.chars.len() >= self.release_at {
+            self.release();
+        }
+    }
+
+    ///
+    /// forget chars at and after `char_offset` because they are pushed back to the stream.
+    ///
+    pub fn truncate(&mut self, char_offset: usize) {
+        self.last = None;
+        if char_offset < self.start {
+            self.chars.clear();
+            self.start = char_offset;
+        }else{
+            self.chars.truncate(char_offset - self.start);
+        }
+    }
+
+    pub fn last(&self) -> Option<char> {
+        self.chars.back().cloned()
+    }
+
+    ///
+    /// drop chars older than the oldest outstanding mark.
+    ///
+    pub fn release(&mut self) {
+        self.marks.retain(|mark| mark.upgrade().is_some());
+
+        let oldest = self.marks.iter()
+            .filter_map(|mark| mark.upgrade())
+            .map(|checkpoint| checkpoint.position.char_offset)
+            .min();
+        match oldest {
+            Some(oldest) => {
+                if oldest > self.start {
+                    let count = (oldest - self.start).min(self.chars.len());
+                    self.chars.drain(..count);
+                    self.start += count;
+                }
+            },
+            None => {
+                let end = self.start + self.chars.len();
+                self.clear(end);
+            },
+        }
+        self.release_at = (self.chars.len().max(self.marks.len()) * 2).max(MIN_RELEASE_AT);
+    }
+
+    fn clear(&mut self, start: usize) {
+        self.chars.clear();
+        self.chars.shrink_to_fit();
+        self.marks.clear();
+        self.start = start;
+        self.release_at = MIN_RELEASE_AT;
+        self.keeps_chars = !self.seekable;
+    }
+}
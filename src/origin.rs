@@ -0,0 +1,30 @@
+///
+/// where the char read last from CharStream came from.
+///
+/// Example:
+///
+/// ```
+/// use char_stream::{CharStream, Origin};
+///
+/// let mut stream = CharStream::from("f(x)");
+/// stream.next();
+/// stream.splice_str("g").unwrap();
+/// assert_eq!(Some('g'), stream.next());
+/// assert_eq!(Origin::Expansion { depth: 1 }, stream.origin());
+/// assert_eq!(Some('('), stream.next());
+/// assert_eq!(Origin::Original, stream.origin());
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    ///
+    /// the original input of the stream.
+    ///
+    Original,
+
+    ///
+    /// a stream spliced by `CharStream::splice`.
+    /// `depth` is 1 for a stream spliced into the original input, and 2 for one spliced into it.
+    ///
+    Expansion { depth: usize },
+}
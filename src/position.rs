@@ -0,0 +1,89 @@
+use std::fmt;
+use line_endings::LineBreak;
+
+///
+/// A position in CharStream.
+///
+/// `line` and `column` start from 1, `char_offset` and `byte_offset` start from 0.
+/// `byte_offset` counts bytes of chars encoded in UTF-8,
+/// except for CharStream made by `from_path_mmap`, where it is the offset in the file.
+/// Lines are counted by `LineEndings` of the stream.
+///
+/// Example:
+///
+/// ```
+/// use char_stream::CharStream;
+///
+/// let mut stream = CharStream::from("世界\r\n❤");
+/// stream.read_line();
+///
+/// let position = stream.position();
+/// assert_eq!(2, position.line);
+/// assert_eq!(1, position.column);
+/// assert_eq!(4, position.char_offset);
+/// assert_eq!(8, position.byte_offset);
+/// ```
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    pub char_offset: usize,
+    pub byte_offset: usize,
+}
+
+impl Position {
+    ///
+    /// the start of stream.
+    ///
+    pub fn new() -> Position {
+        Position {
+            line: 1,
+            column: 1,
+            char_offset: 0,
+            byte_offset: 0,
+        }
+    }
+
+    ///
+    /// move forward over the char.
+    ///  the second char of "\r\n" which is already a line break doesn't move the column.
+    ///
+    pub(crate) fn advance(&mut self, c: char, line_break: LineBreak) {
+        self.char_offset += 1;
+        self.byte_offset += c.len_utf8();
+        match line_break {
+            LineBreak::Yes => {
+                self.line += 1;
+                self.column = 1;
+            },
+            LineBreak::Continued => {},
+            LineBreak::No => self.column += 1,
+        }
+    }
+
+    ///
+    /// position which is `relative` from this position.
+    ///  `relative` is counted from `Position::new()` as if the stream started here.
+    ///
+    pub(crate) fn join(self, relative: Position) -> Position {
+        Position {
+            line: self.line + relative.line - 1,
+            column: if relative.line == 1 { self.column + relative.column - 1 } else { relative.column },
+            char_offset: self.char_offset + relative.char_offset,
+            byte_offset: self.byte_offset + relative.byte_offset,
+        }
+    }
+}
+
+impl Default for Position {
+    fn default() -> Position {
+        Position::new()
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+use Error;
+
+///
+/// A source of chars which can be read through CharStream.
+///
+/// Implement this trait to plug your own backend into CharStream
+/// with `CharStream::from_source`, which takes a source that is also `Send`.
+///
+/// Example:
+///
+/// ```
+/// use char_stream::{CharSource, CharStream};
+///
+/// struct Repeat {
+///     c: char,
+///     count: usize,
+///     position: usize,
+/// }
+///
+/// impl CharSource for Repeat {
+///     fn next(&mut self) -> Option<char> {
+///         let result = self.peek();
+///         if result.is_some() {
+///             self.position += 1;
+///         }
+///         result
+///     }
+///
+///     fn peek(&mut self) -> Option<char> {
+///         if self.position < self.count {
+///             Some(self.c)
+///         }else{
+///             None
+///         }
+///     }
+///
+///     fn position(&self) -> usize {
+///         self.position
+///     }
+/// }
+///
+/// let mut stream = CharStream::from_source(Box::new(Repeat { c: 'a', count: 3, position: 0 }));
+/// assert_eq!("aaa", stream.to_string());
+/// ```
+///
+pub trait CharSource {
+    ///
+    /// read a next char
+    ///
+    fn next(&mut self) -> Option<char>;
+
+    ///
+    /// peek a next char
+    ///
+    fn peek(&mut self) -> Option<char>;
+
+    ///
+    /// count of chars already read
+    ///
+    fn position(&self) -> usize;
+
+    ///
+    /// whether there is no more char to read
+    ///
+    fn eof(&mut self) -> bool {
+        self.peek().is_none()
+    }
+
+    ///
+    /// read a next char, reporting an error which ended the source.
+    ///
+    /// The default implementation never fails.
+    ///
+    fn try_next(&mut self) -> Result<Option<char>, Error> {
+        Ok(self.next())
+    }
+
+    ///
+    /// peek a next char, reporting an error which ended the source.
+    ///
+    /// The default implementation never fails.
+    ///
+    fn try_peek(&mut self) -> Result<Option<char>, Error> {
+        Ok(self.peek())
+    }
+}
+
+impl<'a> fmt::Debug for dyn CharSource + 'a {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        debug(self, f)
+    }
+}
+
+impl<'a> fmt::Debug for dyn CharSource + Send + 'a {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        debug(self, f)
+    }
+}
+
+fn debug(source: &dyn CharSource, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CharSource")
+        .field("position", &source.position())
+        .finish()
+}
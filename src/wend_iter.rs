@@ -1,15 +1,50 @@
-use std::iter::{Iterator, DoubleEndedIterator};
-use internals::InternalCharVec;
+use std::iter::{Iterator, DoubleEndedIterator, ExactSizeIterator};
+use internals::{InternalCharVec, InternalMmap, InternalReverseFile};
+use Error;
 
+///
+/// DoubleEndedIterator made by `CharStream::wend_iter`.
+///
+/// `next` and `next_back` meet in the middle, so each char is yielded only once.
+/// A file is read by blocks from both ends, so it is never loaded into memory as a whole.
+/// Its chars are counted when the iterator is made, which decodes the file once.
+/// An I/O error ends the iteration, and can be taken by `take_error`.
+/// A memory-mapped file is read directly from the mapped bytes.
+///
 #[derive(Debug)]
 pub enum WendIterator {
     Chars { chars: InternalCharVec },
+    File { file: InternalReverseFile },
+    Mmap { mmap: InternalMmap },
 }
 
 impl WendIterator {
     pub fn from_chars(chars: InternalCharVec) -> WendIterator {
         WendIterator::Chars {
-            chars: chars
+            chars
+        }
+    }
+
+    pub fn from_file(file: InternalReverseFile) -> WendIterator {
+        WendIterator::File {
+            file
+        }
+    }
+
+    pub fn from_mmap(mmap: InternalMmap) -> WendIterator {
+        WendIterator::Mmap {
+            mmap
+        }
+    }
+
+    ///
+    /// take the error which ended the iteration early.
+    ///  returns None if the iteration was not ended by an error.
+    ///
+    pub fn take_error(&mut self) -> Option<Error> {
+        match *self {
+            WendIterator::File { ref mut file } => file.take_error(),
+            _ => None,
         }
     }
 }
@@ -18,20 +53,99 @@ impl Iterator for WendIterator {
     type Item = char;
 
     fn next(&mut self) -> Option<char> {
-        match self {
-            &mut WendIterator::Chars { ref mut chars } => {
+        match *self {
+            WendIterator::Chars { ref mut chars } => {
                 chars.next()
             },
+            WendIterator::File { ref mut file } => {
+                file.next()
+            },
+            WendIterator::Mmap { ref mut mmap } => {
+                mmap.next()
+            },
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            WendIterator::Chars { ref chars } => {
+                let len = chars.len();
+                (len, Some(len))
+            },
+            WendIterator::File { ref file } => {
+                let len = file.len();
+                (len, Some(len))
+            },
+            WendIterator::Mmap { ref mmap } => {
+                let len = mmap.len();
+                (len, Some(len))
+            },
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<char> {
+        match *self {
+            WendIterator::Chars { ref mut chars } => {
+                chars.nth(n)
+            },
+            WendIterator::File { ref mut file } => {
+                for _ in 0..n {
+                    file.next()?;
+                }
+                file.next()
+            },
+            WendIterator::Mmap { ref mut mmap } => {
+                for _ in 0..n {
+                    mmap.next()?;
+                }
+                mmap.next()
+            },
         }
     }
 }
 
 impl DoubleEndedIterator for WendIterator {
     fn next_back(&mut self) -> Option<char> {
-        match self {
-            &mut WendIterator::Chars { ref mut chars } => {
+        match *self {
+            WendIterator::Chars { ref mut chars } => {
                 chars.next_back()
             },
+            WendIterator::File { ref mut file } => {
+                file.next_back()
+            },
+            WendIterator::Mmap { ref mut mmap } => {
+                mmap.next_back()
+            },
+        }
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<char> {
+        match *self {
+            WendIterator::Chars { ref mut chars } => {
+                chars.nth_back(n)
+            },
+            WendIterator::File { ref mut file } => {
+                for _ in 0..n {
+                    file.next_back()?;
+                }
+                file.next_back()
+            },
+            WendIterator::Mmap { ref mut mmap } => {
+                for _ in 0..n {
+                    mmap.next_back()?;
+                }
+                mmap.next_back()
+            },
         }
     }
-}
\ No newline at end of file
+}
+
+impl ExactSizeIterator for WendIterator {
+    fn len(&self) -> usize {
+        match *self {
+            WendIterator::Chars { ref chars } => chars.len(),
+            WendIterator::File { ref file } => file.len(),
+            WendIterator::Mmap { ref mmap } => mmap.len(),
+        }
+    }
+}
@@ -15,6 +15,7 @@ fn test_rev() {
 }
 
 #[test]
+#[allow(clippy::iter_nth_zero)]
 fn test_nth() {
     // [言語処理100本ノック 2015](http://www.cl.ecei.tohoku.ac.jp/nlp100/)
     // 01. 「パタトクカシーー」
@@ -30,6 +31,7 @@ fn test_nth() {
 }
 
 #[test]
+#[allow(clippy::while_let_on_iterator)]
 fn test_zip() {
     // [言語処理100本ノック 2015](http://www.cl.ecei.tohoku.ac.jp/nlp100/)
     // 02. 「パトカー」＋「タクシー」＝「パタトクカシーー」
@@ -46,4 +48,41 @@ fn test_zip() {
     }
 
     assert_eq!("パタトクカシーー", result);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_wend_iter_meet() {
+    let stream = CharStream::from("abcde");
+    let mut iter = stream.wend_iter();
+    assert_eq!(5, iter.len());
+    assert_eq!(Some('a'), iter.next());
+    assert_eq!(Some('e'), iter.next_back());
+    assert_eq!(Some('b'), iter.next());
+    assert_eq!(Some('d'), iter.next_back());
+    assert_eq!((1, Some(1)), iter.size_hint());
+    assert_eq!(Some('c'), iter.next_back());
+    assert_eq!(None, iter.next());
+    assert_eq!(None, iter.next_back());
+    assert_eq!(0, iter.len());
+}
+
+#[test]
+fn test_wend_iter_nth_back() {
+    let stream = CharStream::from("パタトクカシーー");
+    let mut iter = stream.wend_iter();
+    assert_eq!(Some('ー'), iter.nth_back(1));
+    assert_eq!(Some('パ'), iter.next());
+    assert_eq!(Some('カ'), iter.nth_back(1));
+    assert_eq!(3, iter.len());
+    assert_eq!(None, iter.nth_back(3));
+    assert_eq!(None, iter.next());
+}
+
+#[test]
+fn test_wend_iter_rev_collect() {
+    let stream = CharStream::from("stressed");
+    let mut iter = stream.wend_iter();
+    assert_eq!(Some('s'), iter.next());
+    let result: String = iter.rev().collect();
+    assert_eq!("dessert", result);
+}